@@ -1,17 +1,29 @@
 use clap::Parser;
 use colored::control::set_override;
 use core::panic;
-use iced_x86::{FormatterOutput, FormatterTextKind};
+use iced_x86::{FormatterOutput, FormatterTextKind, Instruction, Mnemonic, Register};
 use rayon::prelude::*;
 use regex::Regex;
 use ropr::{
-	binary::Binary, disassembler::Disassembly, formatter::ColourFormatter, gadgets::Gadget,
+	binary::{Binary, Bitness, Section, SymbolIndex},
+	error::ErrorReport,
+	rules::{direct_branch_target, gadget_tail_rule, parse_mnemonic, parse_register, TailRule},
+	disassembler::{basic_block_start, decode_gadget, detect_return_thunk, Disassembly},
+	formatter::ColourFormatter,
+	gadgets::{format_instructions, parse_gadget_line, Gadget, HexStyle, RegisterSource},
 };
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde::{Deserialize, Serialize};
 use std::{
 	error::Error,
-	io::{stdout, BufWriter, Write},
-	path::PathBuf,
+	fs::File,
+	io::{stdin, stdout, BufRead, BufReader, BufWriter, Write},
+	path::{Path, PathBuf},
+	str::FromStr,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Mutex,
+	},
 	time::Instant,
 };
 
@@ -34,11 +46,35 @@ struct Opt {
 	#[clap(short = 's', long)]
 	nosys: bool,
 
+	/// Removes iret gadgets
+	#[clap(long)]
+	no_iret: bool,
+
+	/// Removes sysret gadgets
+	#[clap(long)]
+	no_sysret: bool,
+
+	/// Removes sysexit gadgets
+	#[clap(long)]
+	no_sysexit: bool,
+
+	/// Removes gadgets ending in `syscall`, `sysenter` or `int 0x80`
+	#[clap(long)]
+	nosyscall: bool,
+
 	/// Removes "JOP Gadgets" - these may have a controllable branch, call, etc. instead of a simple `ret` at the end
 	#[clap(short = 'j', long)]
 	nojop: bool,
 
-	/// Filters for gadgets which alter the stack pointer
+	/// Keeps "COP Gadgets" ending in a register or memory `call` even with `--nojop`, eg. `--nojop --cop` for only call dispatchers
+	#[clap(long, conflicts_with = "nocop")]
+	cop: bool,
+
+	/// Removes "COP Gadgets" ending in a register or memory `call`
+	#[clap(long)]
+	nocop: bool,
+
+	/// Filters for gadgets which alter the stack pointer, frame teardowns controlled by rbp (`mov rsp, rbp` or `leave`) are tagged `[rbp-pivot]`
 	#[clap(short = 'p', long)]
 	stack_pivot: bool,
 
@@ -46,153 +82,1478 @@ struct Opt {
 	#[clap(short = 'b', long)]
 	base_pivot: bool,
 
+	/// Removes gadgets containing a conditional move (`cmovcc`), whose effect depends on flags, this includes the `cmovcc rsp` heads of `--stack-pivot` and `--base-pivot` gadgets
+	#[clap(long)]
+	no_cmov: bool,
+
+	/// Filters for gadgets which restore many registers before a `ret`, `popa`/`popad` or a run of at least 3 consecutive pops, appending the registers restored
+	#[clap(long)]
+	popad: bool,
+
+	/// Filters for gadgets which load a register from the stack (`mov reg, [rsp/rbp+disp]`) before a `ret`
+	#[clap(long)]
+	load_from_stack: bool,
+
+	/// Filters for gadgets which copy the stack pointer into another register (`mov reg, rsp` or `lea reg, [rsp+disp]`) before a `ret`, leaking a stack address
+	#[clap(long)]
+	leak_rsp: bool,
+
+	/// Filters for JOP dispatcher gadgets which modify a register and then branch through it
+	#[clap(long)]
+	dispatcher: bool,
+
+	/// Filters for gadgets which branch through a register loaded from memory (`mov rax, [rbx+off]; jmp rax`)
+	#[clap(long)]
+	vtable_gadgets: bool,
+
+	/// Filters for gadgets with `cld`/`std` or `movs`/`stos` string copies (`rep` prefixed copies also need `--noisy`)
+	#[clap(long)]
+	string_ops: bool,
+
+	/// Filters for write-what-where gadgets which `mov` a register to memory addressed by registers
+	#[clap(long)]
+	write_gadgets: bool,
+
+	/// Filters for gadgets with any instruction writing to a memory operand, eg. `mov qword ptr [rsp], rax` or `add [rdi], eax`
+	#[clap(long)]
+	writes_mem: bool,
+
+	/// Filters for gadgets which copy memory through a register, eg. `mov rax, [rsi]; mov [rdi], rax`
+	#[clap(long)]
+	mem_copy: bool,
+
+	/// Filters for gadgets which can safely be run repeatedly: no memory writes, no register both read
+	/// and written and no flags both read and written (the stack pointer is ignored)
+	#[clap(long)]
+	idempotent: bool,
+
+	/// Only keep write gadgets whose destination uses a scaled index, eg. `mov [rax+rcx*8], rdx` (implies `--write-gadgets`)
+	#[clap(long)]
+	scaled_write: bool,
+
+	/// Filters for gadgets which start at a function prologue (`endbr64` or `push rbp; mov rbp, rsp`)
+	#[clap(long)]
+	prologue_only: bool,
+
+	/// Filters for gadgets which push a register onto the stack before a `ret`, optionally a specific register eg. `--push-reg=rax`
+	#[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "any")]
+	push_reg: Option<String>,
+
+	/// Filters for gadgets which mask, set bits in or shift a register by an immediate (`and`/`or`/`shl`/`shr`/`sar`), eg. `--bitop rax`
+	#[clap(long)]
+	bitop: Option<String>,
+
+	/// When `__x86_return_thunk` has no symbol (eg. a stripped kernel), treats the most common `jmp` target which looks like a return thunk (`ret; int3`) as the return thunk
+	#[clap(long)]
+	detect_return_thunk: bool,
+
 	/// Maximum number of instructions in a gadget
 	#[clap(short, long, default_value = "6")]
 	max_instr: u8,
 
+	/// Filters for gadgets whose tail branches through the GOT entry of the named import, implies `--noisy` as these branches are RIP-relative
+	#[clap(long)]
+	call_import: Option<String>,
+
+	/// Skips head positions which can't reach the tail up front instead of walking each one, faster with a large `--max-instr`
+	#[clap(long)]
+	prune_heads: bool,
+
+	/// Drops tails which lie in the immediate or displacement bytes of an instruction when the section is disassembled linearly, eg. the `c3` of `mov eax, 0xc3`
+	#[clap(long)]
+	no_embedded_ret: bool,
+
+	/// Drops gadgets whose first instruction borrows prefix bytes (legacy or REX) from a different instruction of a linear disassembly of the section, even with `--noisy`
+	#[clap(long)]
+	no_orphan_prefix: bool,
+
+	/// Only keeps `jmp` and `call` ended gadgets which start at an `endbr64` or directly after one, the only targets indirect branches can reach with CET. `ret` ended gadgets are unaffected
+	#[clap(long)]
+	endbr: bool,
+
+	/// Drops gadgets ending in `ret imm16`, which release extra stack after returning, keeping only a plain `ret`
+	#[clap(long)]
+	no_ret_imm: bool,
+
+	/// Number of bytes scanned by each parallel work item, for tuning scans of very large sections
+	#[clap(long, default_value = "4096")]
+	scan_chunk: usize,
+
+	/// Maximum number of distinct general purpose registers a gadget may use, not counting the stack pointer
+	#[clap(long)]
+	max_regs: Option<usize>,
+
+	/// Maximum length of a gadget in bytes, tail included
+	#[clap(long)]
+	max_bytes: Option<usize>,
+
+	/// Minimum net change to the stack pointer, tail included, in decimal or `0x` hex, eg. `--min-stack-delta 0x18`. Gadgets whose change isn't constant are dropped
+	#[clap(long, value_parser = parse_stack_delta, allow_hyphen_values = true)]
+	min_stack_delta: Option<i64>,
+
+	/// Maximum net change to the stack pointer, tail included, see `--min-stack-delta`
+	#[clap(long, value_parser = parse_stack_delta, allow_hyphen_values = true)]
+	max_stack_delta: Option<i64>,
+
+	/// Only keeps gadgets which load this register with a `pop` or register `mov` as their first instruction and leave it alone until the tail, eg. `--set rdi`
+	#[clap(long)]
+	set: Option<String>,
+
+	/// Drops gadgets which write any of these comma separated registers, partial writes included, eg. `--avoid-regs rdi,rsi`. `rsp` only drops gadgets which move the stack pointer by a non-constant amount
+	#[clap(long, value_delimiter = ',')]
+	avoid_regs: Vec<String>,
+
+	/// Maximum number of gadgets to keep for each tail instruction, the shortest are kept
+	#[clap(long)]
+	per_tail_limit: Option<usize>,
+
+	/// Accepts direct `jmp`/`call` instructions to a constant address as tails (like `--noisy` does), annotating the target's function name when known
+	#[clap(long)]
+	direct_branch_tails: bool,
+
 	/// Perform a regex search on the returned gadgets for easy filtering
 	#[clap(short = 'R', long)]
 	regex: Vec<String>,
 
+	/// Only prints the single shortest gadget left after filtering, the lowest addressed on ties, eg. `-R 'pop rdi' --shortest`
+	#[clap(long)]
+	shortest: bool,
+
+	/// Only keeps gadgets whose first instruction is one of these comma separated mnemonics, eg. `--head-mnemonic pop,xchg`
+	#[clap(long, value_delimiter = ',')]
+	head_mnemonic: Vec<String>,
+
 	/// Perform an inverse regex search on the returned gadgets for easy filtering
 	#[clap(short = 'N')]
 	not_regex: Vec<String>,
 
+	/// Prints the number of gadgets in each class (pop-reg, write, read, syscall, pivot, mov-reg, ...) with a few sample addresses instead of listing them
+	#[clap(long)]
+	report: bool,
+
+	/// Prints a single gadget for each operation (`pop rdi`, `syscall`, `write`, ...), preferring a plain `ret`, then the fewest instructions and the lowest address
+	#[clap(long)]
+	catalog: bool,
+
+	/// Prints `#define POP_RDI_RET 0x1234` lines for the preferred `pop reg; ret`, `syscall; ret` and `ret` gadgets, for including in a C exploit
+	#[clap(long)]
+	c_header: bool,
+
+	/// Like `--c-header` with addresses relative to `_text`, or to the lowest scanned section when there is no such symbol
+	#[clap(long)]
+	c_header_relative: bool,
+
+	/// Prints each gadget as a Python assignment, eg. `rop_pop_rdi = 0x1234  # pop rdi; ret;`, addresses are `base + 0x...` with `--base`
+	#[clap(long)]
+	pwn: bool,
+
+	/// Groups `syscall; ret` gadgets with the gadgets which pop each syscall argument register
+	#[clap(long)]
+	syscall_toolkit: bool,
+
+	/// Lists the gadgets which set the syscall number in `rax` before a `ret`: popped (`pop rax; ret`), constant (`mov eax, 0x3b; ret`) or zeroed (`xor eax, eax; ret`)
+	#[clap(long)]
+	syscall_num: bool,
+
 	/// Treats the input file as a blob of code (`true` or `false`)
 	#[clap(long)]
 	raw: Option<bool>,
 
+	/// Decodes raw blobs as 16, 32 or 64-bit code, by default `--raw true` is 64-bit and unrecognised files are 32-bit
+	#[clap(long, value_parser = parse_bitness)]
+	bitness: Option<Bitness>,
+
+	/// Scans these ELF sections instead of `.text`, eg. `--section .text --section .noinstr.text`, `all-exec` scans every executable section
+	#[clap(long)]
+	section: Vec<String>,
+
+	/// Applies kernel `.altinstructions` patches before searching, as if every cpu feature were present (`true` or `false`, defaults to `true`)
+	#[clap(long)]
+	patch_alternatives: Option<bool>,
+
 	/// Search between address ranges (in hexadecial) eg. `0x1234-0x4567`
 	#[clap(long)]
 	range: Vec<String>,
 
+	/// Exclude gadgets between address ranges (in hexadecial) eg. `0x1234-0x4567`
+	#[clap(long)]
+	exclude_range: Vec<String>,
+
+	/// Drops gadgets whose address, little-endian at the pointer width, contains any of these comma separated hex bytes, eg. `00,0a,20`
+	#[clap(long, value_delimiter = ',')]
+	bad_bytes: Vec<String>,
+
+	/// Follows each gadget's address with the symbol it's in, eg. `0x00001234 <main+0x10>: pop rdi; ret;`
+	#[clap(long)]
+	symbols: bool,
+
+	/// Prints gadget addresses relative to this hex address or symbol, eg. `--base _text`, ranges and filters still use absolute addresses
+	#[clap(long)]
+	base: Option<String>,
+
 	/// Show duplicated gadgets
 	#[clap(short = 'u', long)]
 	nouniq: bool,
 
-	/// Alphabetically sort gadget output
+	/// Lists each distinct gadget text once, at the lowest address it was found
 	#[clap(long)]
-	sort: bool,
+	unique_text: bool,
+
+	/// Style of hex numbers in the output (`lower`, `upper` or `masm`)
+	#[clap(long, default_value = "lower")]
+	hex_style: HexStyle,
+
+	/// Stops scanning once this many distinct gadgets passing the filters are found, these are not the lowest addressed gadgets
+	#[clap(long)]
+	preview: Option<usize>,
+
+	/// Prints gadget addresses as offsets into their section, eg. `.text+0x1234`
+	#[clap(long)]
+	section_relative: bool,
+
+	/// Appends the rule which accepted each gadget's tail, eg. `[ret]`, `[jop-jmp]` or `[thunk]`
+	#[clap(long)]
+	tag_tail_rule: bool,
+
+	/// Prints RIP-relative operands as the absolute address they refer to, eg. `lea rax, [0x1234]` instead of `lea rax, [rip+0x1000]`
+	#[clap(long)]
+	resolve_rip: bool,
 
-	/// The path of the file to inspect
-	binary: PathBuf,
+	/// Appends a summary of what each gadget does, eg. `[pops rdi, rsi; returns (stack +0x18)]`
+	#[clap(long)]
+	describe: bool,
+
+	/// Appends the address of each gadget's tail instruction, eg. `[tail 0x1234]`
+	#[clap(long)]
+	show_tail_addr: bool,
+
+	/// Appends the basic block each gadget sits in, from the nearest preceding control-flow instruction to the gadget's tail, eg. `[bb 0x1000-0x1010]`
+	#[clap(long)]
+	bb_context: bool,
+
+	/// Sorts gadget output by `address` (default), `text`, `length`, `quality` or `regs`, eg. `--sort=length`, `--sort` alone sorts by text
+	#[clap(long, num_args = 0..=1, require_equals = true, default_value = "address", default_missing_value = "text")]
+	sort: SortKey,
+
+	/// Sorts gadgets by instruction count and then address, shortest first, same as `--sort=length`
+	#[clap(long, conflicts_with = "sort")]
+	sort_length: bool,
+
+	/// Saves the scanned gadgets (before any filtering other than `--nouniq`, the address ranges and `--bad-bytes`) to a msgpack file
+	#[clap(long)]
+	dump_bin: Option<PathBuf>,
+
+	/// Loads gadgets from a file written by `--dump-bin` for the same binary and ropr version instead of scanning, the binary is still used for symbols
+	#[clap(long)]
+	load_bin: Option<PathBuf>,
+
+	/// Merges gadgets read from stdin in ropr's output format (`0x1234: pop rdi; ret;`) with the scanned gadgets, each is re-decoded from the binary
+	#[clap(long)]
+	merge_stdin: bool,
+
+	/// Re-decodes every gadget from the binary's bytes and reports any whose instructions differ, eg. gadgets loaded with `--load-bin` from a different build
+	#[clap(long)]
+	verify: bool,
+
+	/// Writes the gadgets to this file instead of stdout, in plain text unless `--colour true` is given
+	#[clap(short = 'o', long)]
+	output: Option<PathBuf>,
+
+	/// With `--output`, also prints the gadgets to stdout
+	#[clap(long, requires = "output")]
+	tee: bool,
+
+	/// Validates the options and prints the sections, ranges, regexes and settings a search would use, without searching
+	#[clap(long)]
+	dry_run: bool,
+
+	/// Formats errors as `text` (default) or `json` objects on stderr, eg. `{"error": "...", "kind": "file-not-found"}`
+	#[clap(long, default_value = "text")]
+	error_format: ErrorFormat,
+
+	/// Resolves symbols from this file instead of the binary, eg. the debug file of a stripped `vmlinux`
+	#[clap(long)]
+	symbols_from: Option<PathBuf>,
+
+	/// Tallies every distinct indirect `jmp`/`call` tail in the binary, eg. `jmp rax` or `call qword ptr [rbx+8]`, and exits
+	#[clap(long)]
+	branch_targets: bool,
+
+	/// Prints only the number of gadgets left after filtering, without formatting them
+	#[clap(long)]
+	count: bool,
+
+	/// Writes the gadgets as a JSON array of objects (see `--json-schema`) instead of text, never in colour
+	#[clap(long)]
+	json: bool,
+
+	/// Prints the JSON Schema of the gadget objects in JSON output and exits
+	#[clap(long, exclusive = true)]
+	json_schema: bool,
+
+	/// The path of the file to inspect, `-` reads it from stdin
+	#[clap(required_unless_present = "json_schema")]
+	binary: Option<PathBuf>,
 
     /// Print addresses of useful symbols, requires symbols present (overrides all other options)
 	#[clap(long)]
 	magic: bool,
+
+	/// Comma separated symbols for `--magic` to print instead of its kernel defaults (implies `--magic`)
+	#[clap(long, value_delimiter = ',')]
+	magic_syms: Vec<String>,
+
+	/// Reads the symbols for `--magic` from this file, one per line (implies `--magic`)
+	#[clap(long)]
+	magic_file: Option<PathBuf>,
+
+	/// Symbol `--magic` addresses are relative to, `_text` by default
+	#[clap(long, default_value = "_text")]
+	magic_base: String,
 }
 
-fn write_gadgets(
-    mut w: impl Write,
-    gadgets: &[(Gadget, usize)],
-    ret_thunk: Option<u64>,
-    thunks: &Vec<(String, Option<u64>)>,
-    jump_thunks: &Vec<(String, Option<u64>)>,
-    call_thunks: &Vec<(String, Option<u64>)>,
-    sort: bool
-) {
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+	Address,
+	/// Alphabetical by the formatted instructions
+	Text,
+	/// Fewest instructions first
+	Length,
+	/// Gadgets ending in `ret` first, then by fewest instructions and fewest registers used
+	Quality,
+	/// Fewest registers used first
+	Regs,
+}
+
+impl FromStr for SortKey {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"address" => Ok(Self::Address),
+			"text" => Ok(Self::Text),
+			"length" => Ok(Self::Length),
+			"quality" => Ok(Self::Quality),
+			"regs" => Ok(Self::Regs),
+			_ => Err(format!(
+				"unknown sort key `{}`, expected address, text, length, quality or regs",
+				s
+			)),
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ErrorFormat {
+	Text,
+	Json,
+}
+
+impl FromStr for ErrorFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"text" => Ok(Self::Text),
+			"json" => Ok(Self::Json),
+			_ => Err(format!("unknown error format `{}`, expected text or json", s)),
+		}
+	}
+}
+
+/// Settings shared by everything that prints gadgets
+struct OutputOptions<'a> {
+	ret_thunk: Option<u64>,
+	thunks: &'a [(String, Option<u64>)],
+	jump_thunks: &'a [(String, Option<u64>)],
+	call_thunks: &'a [(String, Option<u64>)],
+	sort: bool,
+	hex_style: HexStyle,
+	sections: &'a [Section<'a>],
+	/// Subtracted from printed addresses, see `--base`
+	base: usize,
+	/// Print addresses as offsets into the section containing them
+	section_relative: bool,
+	/// Append the extent of the basic block containing the gadget
+	bb_context: bool,
+	/// Append the registers restored by `popa`/`popad` or a run of pops
+	tag_restored: bool,
+	/// Print RIP-relative operands as the absolute address they refer to
+	resolve_rip: bool,
+	/// Append a summary of what the gadget does
+	describe: bool,
+	/// Symbols to annotate each gadget's address with, eg. `<main+0x10>`
+	symbols: Option<&'a SymbolIndex>,
+	/// Names of functions, used to annotate the targets of direct branch tails
+	branch_symbols: Option<&'a FxHashMap<u64, String>>,
+	/// Append the address of the gadget's tail instruction
+	show_tail_addr: bool,
+	/// Append the kind of stack pivot, eg. `[rbp-pivot]`
+	tag_pivot: bool,
+	/// Write a JSON array of gadgets instead of text lines
+	json: bool,
+	/// Append the rule which accepted the gadget's tail, `noisy` is needed to classify it
+	tag_tail_rule: bool,
+	noisy: bool,
+}
+
+impl OutputOptions<'_> {
+	fn section_containing(&self, address: usize) -> Option<&Section<'_>> {
+		self.sections.iter().find(|section| {
+			let start = section.program_base() + section.section_vaddr();
+			start <= address && address < start + section.bytes().len()
+		})
+	}
+
+	/// The address of a gadget, followed by the symbol containing it when `symbols` is set
+	fn format_gadget_address(&self, address: usize) -> String {
+		let formatted = self.format_address(address);
+		match self.symbols.and_then(|symbols| symbols.lookup(address as u64)) {
+			Some((name, 0)) => format!("{} <{}>", formatted, name),
+			Some((name, offset)) => {
+				format!("{} <{}+{}>", formatted, name, self.hex_style.format_value(offset))
+			}
+			None => formatted,
+		}
+	}
+
+	fn format_address(&self, address: usize) -> String {
+		let section = self.section_containing(address).filter(|_| self.section_relative);
+		match section {
+			Some(section) => {
+				let start = section.program_base() + section.section_vaddr();
+				format!("{}+{}", section.name(), self.hex_style.format_value((address - start) as u64))
+			}
+			None if address < self.base => {
+				format!("-{}", self.hex_style.format_value((self.base - address) as u64))
+			}
+			None => self.hex_style.format_address(address - self.base),
+		}
+	}
+}
+
+/// A destination for gadget output, writers which fail (eg. a closed pipe) stop receiving output
+struct Sink<'w> {
+	writer: Box<dyn Write + 'w>,
+	colour: bool,
+	closed: bool,
+}
+
+impl<'w> Sink<'w> {
+	fn new(writer: impl Write + 'w, colour: bool) -> Self {
+		Self {
+			writer: Box::new(writer),
+			colour,
+			closed: false,
+		}
+	}
+}
+
+/// Writes a line to every open sink, returns false once all of them are closed
+fn write_line(sinks: &mut [Sink], line: &ColourFormatter) -> bool {
+	for sink in sinks.iter_mut().filter(|sink| !sink.closed) {
+		let result = if sink.colour {
+			writeln!(sink.writer, "{}", line)
+		}
+		else {
+			writeln!(sink.writer, "{}", line.plain())
+		};
+		sink.closed = result.is_err();
+	}
+	sinks.iter().any(|sink| !sink.closed)
+}
+
+/// Consecutive pops needed for `--popad` to treat a gadget as restoring registers
+const MIN_RESTORE_POPS: usize = 3;
+
+/// The gadget's instructions as printed, with thunks and branch targets named
+fn format_gadget_text(gadget: &Gadget, address: usize, opts: &OutputOptions) -> String {
+	let hex_style = opts.hex_style;
+	let mut formatted = String::new();
+	if opts.resolve_rip {
+		// Deduplicated gadgets keep the instructions of another occurrence, re-decode them at this
+		// address so RIP-relative operands resolve against the right IP
+		let decoded = opts
+			.section_containing(address)
+			.and_then(|section| decode_gadget(section, address, gadget.instructions().len(), true));
+		let instructions = decoded.as_ref().map_or(gadget.instructions(), |(g, _)| g.instructions());
+		format_instructions(instructions, &mut formatted, hex_style, true);
+	}
+	else {
+		gadget.format_instruction_styled(&mut formatted, hex_style);
+	}
+
+	if let Some(ret_thunk) = opts.ret_thunk {
+		let ret_thunk = hex_style.format_value(ret_thunk);
+		formatted = formatted.replace(
+			&format!("{ret_thunk};"),
+			&format!("{ret_thunk} <__x86_return_thunk>;"),
+		);
+	}
+
+	// Replace addresses of thunks, jump_thunks, and call_thunks with their names
+	let replace_thunk_addresses = |thunks: &[(String, Option<u64>)], formatted: &mut String| {
+		for (name, address) in thunks {
+			if let Some(addr) = address {
+				let addr = hex_style.format_value(*addr);
+				*formatted = formatted.replace(&addr, &format!("{addr} <{name}>"));
+			}
+		}
+	};
+	replace_thunk_addresses(opts.thunks, &mut formatted);
+	replace_thunk_addresses(opts.jump_thunks, &mut formatted);
+	replace_thunk_addresses(opts.call_thunks, &mut formatted);
+
+	let direct_target = gadget.instructions().last().and_then(direct_branch_target);
+	let symbol = direct_target.zip(opts.branch_symbols).and_then(|(target, symbols)| symbols.get(&target));
+	if let (Some(target), Some(symbol)) = (direct_target, symbol) {
+		let target = hex_style.format_value(target);
+		if let Some(stripped) = formatted.strip_suffix(&format!("{target};")) {
+			formatted = format!("{stripped}{target} <{symbol}>;");
+		}
+	}
+	formatted
+}
+
+/// Appends the `[...]` annotations chosen in `opts` to a formatted gadget
+fn push_gadget_tags(formatted: &mut String, gadget: &Gadget, address: usize, opts: &OutputOptions) {
+	if opts.tag_tail_rule {
+		let rule = gadget.instructions().last().and_then(|tail| {
+			gadget_tail_rule(
+				tail, true, true, true, true, true, true, true, true, opts.noisy, opts.ret_thunk,
+				opts.thunks, opts.jump_thunks, opts.call_thunks,
+			)
+		});
+		if let Some(rule) = rule {
+			formatted.push_str(&format!(" [{}]", rule));
+		}
+	}
+
+	if opts.tag_restored {
+		if let Some(registers) = gadget.restored_registers(MIN_RESTORE_POPS, opts.ret_thunk) {
+			let registers = registers.iter().map(|r| format!("{:?}", r).to_lowercase()).collect::<Vec<_>>();
+			formatted.push_str(&format!(" [restores {}]", registers.join(", ")));
+		}
+	}
+
+	if opts.describe {
+		formatted.push_str(&format!(" [{}]", gadget.describe(opts.ret_thunk, opts.hex_style)));
+	}
+
+	if opts.show_tail_addr {
+		formatted.push_str(&format!(" [tail {}]", opts.format_address(address + gadget.tail_offset())));
+	}
+
+	if opts.tag_pivot {
+		if let Some(kind) = gadget.pivot_kind() {
+			formatted.push_str(&format!(" [{}]", kind));
+		}
+	}
+
+	if opts.bb_context {
+		let block_start = opts
+			.section_containing(address)
+			.and_then(|section| basic_block_start(section, address))
+			.map_or_else(|| String::from("?"), |start| opts.format_address(start));
+		// Deduplicated gadgets keep the instructions of another occurrence, so measure rather than use their IPs
+		let block_end = address + gadget.byte_len();
+		formatted.push_str(&format!(" [bb {}-{}]", block_start, opts.format_address(block_end)));
+	}
+}
+
+fn write_gadgets(sinks: &mut [Sink], gadgets: &[(Gadget, usize)], opts: &OutputOptions) {
+	if opts.json {
+		let mut json_gadgets = gadgets
+			.par_iter()
+			.map(|(gadget, address)| {
+				let file_offset = opts.section_containing(*address).map(|section| {
+					section.file_offset() + (address - section.program_base() - section.section_vaddr())
+				});
+				GadgetJson {
+					address: *address,
+					file_offset,
+					instructions: format_gadget_text(gadget, *address, opts),
+					instruction_count: gadget.instructions().len(),
+					is_stack_pivot: gadget.is_stack_pivot(opts.ret_thunk),
+					is_base_pivot: gadget.is_base_pivot(),
+				}
+			})
+			.collect::<Vec<_>>();
+		if opts.sort {
+			json_gadgets.sort_by(|gadget1, gadget2| gadget1.instructions.cmp(&gadget2.instructions));
+		}
+		write_json_array(sinks, &json_gadgets);
+		return;
+	}
+
+	// Formatting dominates printing a large gadget set, so it runs in parallel into plain strings
+	// which are written out in order. Colours are only applied when a line is written to a sink, so
+	// the global colour override is never read from the worker threads
+	let mut formatted_gadgets = gadgets
+		.par_iter()
+		.map(|(gadget, address)| {
+			let mut formatted = format_gadget_text(gadget, *address, opts);
+			push_gadget_tags(&mut formatted, gadget, *address, opts);
+			(*address, formatted)
+		})
+		.collect::<Vec<_>>();
+	if opts.sort {
+		formatted_gadgets.sort_by(|(_, gadget1), (_, gadget2)| gadget1.cmp(gadget2));
+	}
+
 	let mut output = ColourFormatter::new();
-    let mut formatted_gadgets: Vec<(usize, String)> = vec![];
-	for (gadget, address) in gadgets {
+	for (address, formatted) in formatted_gadgets {
 		output.clear();
+		output.write(&format!("{}: ", opts.format_gadget_address(address)), FormatterTextKind::Function);
+		output.write(&formatted, FormatterTextKind::Text);
+		if !write_line(sinks, &output) {
+			return; // Pipe closed - finished writing gadgets
+		}
+	}
+}
+
+/// A gadget in JSON output, see `GADGET_JSON_SCHEMA`
+#[derive(Serialize)]
+struct GadgetJson {
+	address: usize,
+	file_offset: Option<usize>,
+	instructions: String,
+	instruction_count: usize,
+	is_stack_pivot: bool,
+	is_base_pivot: bool,
+}
+
+/// Writes a JSON array with one element per line
+fn write_json_array(sinks: &mut [Sink], elements: &[impl Serialize]) {
+	let mut line = ColourFormatter::new();
+	line.write("[", FormatterTextKind::Text);
+	if !write_line(sinks, &line) {
+		return;
+	}
+	for (index, element) in elements.iter().enumerate() {
+		let separator = if index + 1 < elements.len() { "," } else { "" };
+		let element = serde_json::to_string(element).expect("gadgets always serialize");
+		line.clear();
+		line.write(&format!("  {}{}", element, separator), FormatterTextKind::Text);
+		if !write_line(sinks, &line) {
+			return;
+		}
+	}
+	line.clear();
+	line.write("]", FormatterTextKind::Text);
+	write_line(sinks, &line);
+}
+
+fn write_syscall_toolkit(sinks: &mut [Sink], gadgets: &[(Gadget, usize)], opts: &OutputOptions) {
+	let mut header = ColourFormatter::new();
+	let syscalls = gadgets
+		.iter()
+		.filter(|(g, _)| g.is_syscall(opts.ret_thunk))
+		.cloned()
+		.collect::<Vec<_>>();
+	header.write("syscall:", FormatterTextKind::Text);
+	write_line(sinks, &header);
+	write_gadgets(sinks, &syscalls, opts);
+
+	let registers = [
+		Register::RAX,
+		Register::RDI,
+		Register::RSI,
+		Register::RDX,
+		Register::R10,
+		Register::R8,
+		Register::R9,
+	];
+	for reg in registers {
+		let pops = gadgets
+			.iter()
+			.filter(|(g, _)| g.pops_register(reg, opts.ret_thunk))
+			.cloned()
+			.collect::<Vec<_>>();
+		header.clear();
+		header.write(&format!("\npop {}:", format!("{:?}", reg).to_lowercase()), FormatterTextKind::Text);
+		write_line(sinks, &header);
+		write_gadgets(sinks, &pops, opts);
+	}
+}
+
+/// Groups the gadgets which set `rax` for a syscall by where the number comes from
+fn write_syscall_numbers(sinks: &mut [Sink], gadgets: &[(Gadget, usize)], opts: &OutputOptions) {
+	let group = |g: &Gadget| match g.register_source(Register::RAX, opts.ret_thunk)? {
+		RegisterSource::Stack => Some(0),
+		RegisterSource::Constant(0) => Some(2),
+		RegisterSource::Constant(_) => Some(1),
+	};
+	let mut header = ColourFormatter::new();
+	for (index, title) in ["pop rax:", "\nconstant rax:", "\nzero rax:"].into_iter().enumerate() {
+		let matching = gadgets
+			.iter()
+			.filter(|(g, _)| group(g) == Some(index))
+			.cloned()
+			.collect::<Vec<_>>();
+		header.clear();
+		header.write(title, FormatterTextKind::Text);
+		if !write_line(sinks, &header) {
+			return;
+		}
+		write_gadgets(sinks, &matching, opts);
+	}
+}
+
+type GadgetPredicate = Box<dyn Fn(&Gadget) -> bool>;
+type GadgetClass = (String, GadgetPredicate);
+
+const POP_REGISTERS: [Register; 15] = [
+	Register::RAX,
+	Register::RBX,
+	Register::RCX,
+	Register::RDX,
+	Register::RSI,
+	Register::RDI,
+	Register::RBP,
+	Register::R8,
+	Register::R9,
+	Register::R10,
+	Register::R11,
+	Register::R12,
+	Register::R13,
+	Register::R14,
+	Register::R15,
+];
+
+/// The semantic classes shared by `--report` and `--catalog`, other than register pops
+fn gadget_classes(ret_thunk: Option<u64>) -> Vec<GadgetClass> {
+	let classes: [(&str, GadgetPredicate); 12] = [
+		("write", Box::new(|g| g.memory_write().is_some())),
+		("mem-copy", Box::new(|g| g.memory_copy().is_some())),
+		("read", Box::new(|g| g.memory_load().is_some())),
+		("mov-reg", Box::new(|g| g.register_move().is_some())),
+		("syscall", Box::new(move |g| g.is_syscall(ret_thunk))),
+		("stack-pivot", Box::new(move |g| g.is_stack_pivot(ret_thunk))),
+		("base-pivot", Box::new(|g| g.is_base_pivot())),
+		("load-from-stack", Box::new(move |g| g.load_from_stack(ret_thunk).is_some())),
+		("push-reg", Box::new(move |g| g.pushed_register(ret_thunk).is_some())),
+		("leak-rsp", Box::new(move |g| g.leaks_rsp(ret_thunk).is_some())),
+		("dispatcher", Box::new(|g| g.is_dispatcher())),
+		("vtable", Box::new(|g| g.vtable_load().is_some())),
+	];
+	classes.into_iter().map(|(name, predicate)| (name.to_string(), predicate)).collect()
+}
+
+fn write_report(sinks: &mut [Sink], gadgets: &[(Gadget, usize)], opts: &OutputOptions) {
+	const SAMPLES: usize = 3;
+	let ret_thunk = opts.ret_thunk;
+	let pop_reg: GadgetClass = (
+		String::from("pop-reg"),
+		Box::new(move |g| POP_REGISTERS.iter().any(|r| g.pops_register(*r, ret_thunk))),
+	);
+	let mut line = ColourFormatter::new();
+	for (class, predicate) in std::iter::once(pop_reg).chain(gadget_classes(ret_thunk)) {
+		let matching = gadgets.iter().filter(|(g, _)| predicate(g)).map(|(_, address)| *address);
+		let samples = matching.clone().take(SAMPLES).map(|a| opts.format_address(a)).collect::<Vec<_>>();
+		line.clear();
+		line.write(&format!("{:<16}", class), FormatterTextKind::Mnemonic);
+		line.write(&format!("{:>8}", matching.count()), FormatterTextKind::Text);
+		if !samples.is_empty() {
+			line.write("  ", FormatterTextKind::Text);
+			line.write(&samples.join(", "), FormatterTextKind::Function);
+		}
+		if !write_line(sinks, &line) {
+			return;
+		}
+	}
+}
+
+/// Chooses the most reliable gadget matching `predicate`, preferring a plain `ret` tail, then the
+/// fewest instructions and then the lowest address
+fn preferred_gadget(
+	gadgets: &[(Gadget, usize)],
+	predicate: impl Fn(&Gadget) -> bool,
+	ret_thunk: Option<u64>,
+) -> Option<&(Gadget, usize)> {
+	gadgets.iter().filter(|(g, _)| predicate(g)).min_by_key(|(g, address)| {
+		let ret_imm = g.ret_immediate().is_some();
+		(!g.ends_in_ret(ret_thunk), ret_imm, g.instructions().len(), *address)
+	})
+}
+
+/// Prints one gadget for each operation, see `preferred_gadget`
+fn write_catalog(sinks: &mut [Sink], gadgets: &[(Gadget, usize)], opts: &OutputOptions) {
+	let ret_thunk = opts.ret_thunk;
+	let pops = POP_REGISTERS.into_iter().map(|reg| -> GadgetClass {
+		(
+			format!("pop {}", format!("{:?}", reg).to_lowercase()),
+			Box::new(move |g| g.pops_register(reg, ret_thunk)),
+		)
+	});
+	let mut line = ColourFormatter::new();
+	for (operation, predicate) in pops.chain(gadget_classes(ret_thunk)) {
+		let (gadget, address) = match preferred_gadget(gadgets, &predicate, ret_thunk) {
+			Some(chosen) => chosen,
+			None => continue,
+		};
+		let mut formatted = String::new();
+		gadget.format_instruction_styled(&mut formatted, opts.hex_style);
+		line.clear();
+		line.write(&format!("{:<16}", operation), FormatterTextKind::Mnemonic);
+		line.write(&format!("{}: ", opts.format_address(*address)), FormatterTextKind::Function);
+		line.write(&formatted, FormatterTextKind::Text);
+		if !write_line(sinks, &line) {
+			return;
+		}
+	}
+}
+
+/// Prints `#define POP_RDI_RET 0x1234` lines for the preferred `pop reg; ret`, `syscall; ret` and
+/// `ret` gadgets, addresses are made relative to `base`
+fn write_c_header(sinks: &mut [Sink], gadgets: &[(Gadget, usize)], opts: &OutputOptions, base: usize) {
+	let ret_thunk = opts.ret_thunk;
+	let pops = POP_REGISTERS.into_iter().map(|reg| -> GadgetClass {
+		(
+			format!("POP_{:?}_RET", reg),
+			Box::new(move |g| g.pops_register(reg, ret_thunk)),
+		)
+	});
+	let defines: [GadgetClass; 2] = [
+		(String::from("SYSCALL_RET"), Box::new(move |g| g.is_syscall(ret_thunk))),
+		(
+			String::from("RET"),
+			Box::new(|g| {
+				matches!(g.instructions(), [t] if t.mnemonic() == Mnemonic::Ret && t.op_count() == 0 && !t.has_rep_prefix())
+			}),
+		),
+	];
+	let mut line = ColourFormatter::new();
+	for (name, predicate) in pops.chain(defines) {
+		let (gadget, address) = match preferred_gadget(gadgets, &predicate, ret_thunk) {
+			Some(chosen) => chosen,
+			None => continue,
+		};
+		let mut formatted = String::new();
+		gadget.format_instruction_styled(&mut formatted, HexStyle::Lower);
+		line.clear();
+		let offset = magic_offset(*address as u64, base as u64);
+		line.write(
+			&format!("#define {:<24} {} // {}", name, offset, formatted),
+			FormatterTextKind::Text,
+		);
+		if !write_line(sinks, &line) {
+			return;
+		}
+	}
+}
 
-        let mut formatted = String::new();
-		gadget.format_instruction(&mut formatted);
+/// Python identifier for a gadget, eg. `rop_mov_rax_rdi_0x8` for `mov rax, [rdi+0x8]; ret;`, the
+/// plain `ret` most gadgets end in is left out
+fn pwn_identifier(formatted: &str) -> String {
+	let text = formatted.trim_end_matches(';');
+	let text = text.strip_suffix("; ret").unwrap_or(text);
+	let mut identifier = String::from("rop");
+	for word in text.split(|c: char| !c.is_ascii_alphanumeric()).filter(|w| !w.is_empty()) {
+		identifier.push('_');
+		identifier.push_str(&word.to_ascii_lowercase());
+	}
+	identifier
+}
+
+/// Prints every gadget as a Python assignment, gadgets with the same text get numbered names
+fn write_pwn(sinks: &mut [Sink], gadgets: &[(Gadget, usize)], opts: &OutputOptions) {
+	let mut seen = FxHashMap::default();
+	let mut line = ColourFormatter::new();
+	for (gadget, address) in gadgets {
+		let mut formatted = String::new();
+		gadget.format_instruction_styled(&mut formatted, HexStyle::Lower);
+		let mut identifier = pwn_identifier(&formatted);
+		let count = seen.entry(identifier.clone()).or_insert(0);
+		*count += 1;
+		if *count > 1 {
+			identifier.push_str(&format!("_{}", count));
+		}
+		let value = match opts.base {
+			0 => format!("{:#x}", address),
+			base if *address < base => format!("base - {:#x}", base - address),
+			base => format!("base + {:#x}", address - base),
+		};
+		line.clear();
+		line.write(&format!("{} = {}  # {}", identifier, value, formatted), FormatterTextKind::Text);
+		if !write_line(sinks, &line) {
+			return;
+		}
+	}
+}
+
+/// Sorts gadgets which are in address order by `sort`, stable sorts break ties by address. Text is
+/// sorted once the gadgets are formatted
+fn sort_gadgets(gadgets: &mut [(Gadget, usize)], sort: SortKey, ret_thunk: Option<u64>) {
+	match sort {
+		SortKey::Address | SortKey::Text => (),
+		SortKey::Length => gadgets.sort_by_key(|(g, _)| g.instructions().len()),
+		SortKey::Quality => gadgets.sort_by_key(|(g, _)| {
+			(!g.ends_in_ret(ret_thunk), g.instructions().len(), g.register_count())
+		}),
+		SortKey::Regs => gadgets.sort_by_key(|(g, _)| g.register_count()),
+	}
+}
+
+/// The gadget with the fewest instructions, the lowest addressed on ties
+fn shortest_gadget(gadgets: Vec<(Gadget, usize)>) -> Option<(Gadget, usize)> {
+	gadgets.into_iter().min_by_key(|(g, address)| (g.instructions().len(), *address))
+}
+
+/// Parses a stack delta in decimal or `0x` prefixed hex, eg. `24`, `0x18` or `-0x8`
+fn parse_stack_delta(s: &str) -> Result<i64, String> {
+	let (negative, digits) = match s.strip_prefix('-') {
+		Some(digits) => (true, digits),
+		None => (false, s),
+	};
+	let value = match digits.strip_prefix("0x") {
+		Some(hex) => i64::from_str_radix(hex, 16),
+		None => digits.parse(),
+	}
+	.map_err(|_| format!("invalid stack delta `{}`", s))?;
+	Ok(if negative { -value } else { value })
+}
 
-        if let Some(ret_thunk) = ret_thunk {
-            formatted = formatted.replace(
-                &format!("{ret_thunk:#x};"),
-                &format!("{ret_thunk:#x} <__x86_return_thunk>;")
-            );
+fn parse_bitness(s: &str) -> Result<Bitness, String> {
+	match s {
+		"16" => Ok(Bitness::Bits16),
+		"32" => Ok(Bitness::Bits32),
+		"64" => Ok(Bitness::Bits64),
+		_ => Err(format!("unknown bitness `{}`, expected 16, 32 or 64", s)),
+	}
+}
+
+/// Parses bytes in hexadecimal, eg. `00`, `0x0a`
+fn parse_bad_bytes(bytes: &[String]) -> Result<Vec<u8>, String> {
+	bytes
+		.iter()
+		.map(|b| {
+			let digits = b.trim().trim_start_matches("0x");
+			u8::from_str_radix(digits, 16).map_err(|_| format!("invalid bad byte `{}`", b))
+		})
+		.collect()
+}
+
+/// Whether any byte of `address`, little-endian at the pointer width of the section holding it, is
+/// one of `bad_bytes`
+fn has_bad_byte(address: usize, sections: &[Section], bad_bytes: &[u8]) -> bool {
+	let section = sections.iter().find(|section| {
+		let start = section.program_base() + section.section_vaddr();
+		start <= address && address < start + section.bytes().len()
+	});
+	let width = match section.map_or(Bitness::Bits64, |section| section.bitness()) {
+		Bitness::Bits16 => 2,
+		Bitness::Bits32 => 4,
+		Bitness::Bits64 => 8,
+	};
+	(address as u64).to_le_bytes()[..width].iter().any(|b| bad_bytes.contains(b))
+}
+
+/// Parses address ranges in hexadecimal, eg. `0x1234-0x4567`, warning about reversed ranges
+fn parse_ranges(ranges: &[String]) -> Result<Vec<(usize, usize)>, String> {
+	ranges
+		.iter()
+		.map(|s| {
+			let parsed = s.split_once('-').and_then(|(from, to)| {
+				let from = usize::from_str_radix(from.strip_prefix("0x").unwrap_or(from), 16).ok()?;
+				let to = usize::from_str_radix(to.strip_prefix("0x").unwrap_or(to), 16).ok()?;
+				Some((from, to))
+			});
+			let (from, to) = parsed.ok_or_else(|| format!("invalid range `{}`", s))?;
+			if to < from {
+				eprintln!("warning: range `{}` is reversed and will never match", s);
+			}
+			Ok((from, to))
+		})
+		.collect()
+}
+
+/// Whether `address` is in any of `ranges`, or there are none, and outside every `exclude_ranges`
+fn in_ranges(address: usize, ranges: &[(usize, usize)], exclude_ranges: &[(usize, usize)]) -> bool {
+	let within = |(from, to): &(usize, usize)| *from <= address && address <= *to;
+	(ranges.is_empty() || ranges.iter().any(within)) && !exclude_ranges.iter().any(within)
+}
+
+/// The index of the first instruction which differs, `None` when both are the same
+fn first_difference<T: PartialEq>(original: &[T], decoded: &[T]) -> Option<usize> {
+	(0..original.len().max(decoded.len())).find(|i| original.get(*i) != decoded.get(*i))
+}
+
+/// Prints the original and re-decoded instructions of a gadget which doesn't match the binary,
+/// with where they first differ
+fn print_mismatch(
+	context: &str,
+	address: usize,
+	original: &str,
+	decoded: &[Instruction],
+	index: usize,
+	hex_style: HexStyle,
+) {
+	let mut formatted = String::new();
+	format_instructions(decoded, &mut formatted, hex_style, false);
+	let offset = decoded.iter().take(index).map(|i| i.len()).sum::<usize>();
+	eprintln!("{}: mismatch at {}", context, hex_style.format_address(address));
+	eprintln!("  original:   {}", original);
+	eprintln!("  re-decoded: {}", formatted);
+	eprintln!(
+		"  first difference at instruction {}, byte offset +{:#x}",
+		index, offset
+	);
+}
+
+/// Re-decodes each gadget at its address, printing a diff of the original and re-decoded
+/// instructions for each mismatch. Returns the number of mismatched gadgets
+fn verify_gadgets(gadgets: &[(Gadget, usize)], sections: &[Section], hex_style: HexStyle) -> usize {
+	let mut mismatches = 0;
+	for (gadget, address) in gadgets {
+		let original = gadget.instructions();
+		let decoded = sections
+			.iter()
+			.find_map(|section| decode_gadget(section, *address, original.len(), false))
+			.map(|(decoded, _)| decoded);
+		let decoded = decoded.as_ref().map_or(&[][..], |g| g.instructions());
+		let index = match first_difference(original, decoded) {
+			Some(index) => index,
+			None => continue,
+		};
+		mismatches += 1;
+		let mut formatted = String::new();
+		format_instructions(original, &mut formatted, hex_style, false);
+		print_mismatch("verify", *address, &formatted, decoded, index, hex_style);
+	}
+	mismatches
+}
+
+/// Where the instructions of a gadget line read by `--merge-stdin` first differ from those decoded
+/// at its address, `None` when they match in any hex style. Names following an address such as
+/// `<__x86_return_thunk>` are ignored
+fn merged_line_difference(
+	instructions: &[String],
+	decoded: &[Instruction],
+	hex_style: HexStyle,
+) -> Option<usize> {
+	let instructions = instructions
+		.iter()
+		.map(|i| i.split(" <").next().unwrap_or(i).to_string())
+		.collect::<Vec<_>>();
+	let texts = |hex_style| {
+		decoded
+			.iter()
+			.map(|i| {
+				let mut formatted = String::new();
+				format_instructions(std::slice::from_ref(i), &mut formatted, hex_style, false);
+				formatted.trim_end_matches(';').to_string()
+			})
+			.collect::<Vec<_>>()
+	};
+	let matches = [HexStyle::Lower, HexStyle::Upper, HexStyle::Masm]
+		.into_iter()
+		.any(|hex_style| first_difference(&instructions, &texts(hex_style)).is_none());
+	match matches {
+		true => None,
+		false => first_difference(&instructions, &texts(hex_style)),
+	}
+}
+
+/// Written ahead of the gadgets by `--dump-bin`. Instructions are saved in iced's own form, which
+/// may change between releases, so a dump is only loaded by the same version of ropr and for the
+/// binary it was taken from
+#[derive(Serialize, Deserialize, PartialEq)]
+struct DumpHeader {
+	format: u32,
+	version: String,
+	binary_crc: u32,
+}
+
+impl DumpHeader {
+	const FORMAT: u32 = 1;
+
+	fn new(binary: &Binary) -> Self {
+		Self {
+			format: Self::FORMAT,
+			version: env!("CARGO_PKG_VERSION").to_string(),
+			binary_crc: binary.checksum(),
+		}
+	}
+}
+
+fn dump_gadgets(
+	path: &Path,
+	binary: &Binary,
+	gadgets: &[(Gadget, usize)],
+) -> Result<(), Box<dyn Error>> {
+	let mut w = BufWriter::new(File::create(path)?);
+	rmp_serde::encode::write(&mut w, &DumpHeader::new(binary))?;
+	rmp_serde::encode::write(&mut w, gadgets)?;
+	w.flush()?;
+	Ok(())
+}
+
+fn load_gadgets(
+	path: &Path,
+	binary: &Binary,
+	uniq: bool,
+) -> Result<Vec<(Gadget, usize)>, Box<dyn Error>> {
+	let mut de = rmp_serde::Deserializer::new(BufReader::new(File::open(path)?));
+	let header = DumpHeader::deserialize(&mut de).map_err(|_| {
+		format!("`{}` is not a gadget dump, or is from an older ropr", path.display())
+	})?;
+	if header.format != DumpHeader::FORMAT || header.version != env!("CARGO_PKG_VERSION") {
+		return Err(format!(
+			"`{}` was dumped by ropr {}, dump the gadgets again with this version",
+			path.display(),
+			header.version
+		)
+		.into());
+	}
+	if header != DumpHeader::new(binary) {
+		return Err(format!("`{}` was dumped from a different binary", path.display()).into());
+	}
+	let gadgets = Vec::<(Gadget, usize)>::deserialize(&mut de)?;
+	Ok(gadgets.into_iter().map(|(g, address)| (g.with_uniq(uniq, address), address)).collect())
+}
+
+fn print_sections(sections: &[Section]) {
+	for section in sections {
+		let start = section.program_base() + section.section_vaddr();
+		let bits = match section.bitness() {
+			Bitness::Bits16 => 16,
+			Bitness::Bits32 => 32,
+			Bitness::Bits64 => 64,
+		};
+		println!(
+			"  {:<16} {:#010x}-{:#010x} file offset {:#x}, {}-bit",
+			section.name(),
+			start,
+			start + section.bytes().len(),
+			section.file_offset(),
+			bits
+		);
+	}
+}
+
+/// JSON Schema for the array of gadget objects written in JSON output mode
+const GADGET_JSON_SCHEMA: &str = r#"{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "title": "ropr gadgets",
+  "type": "array",
+  "items": {
+    "type": "object",
+    "properties": {
+      "address": { "type": "integer", "minimum": 0, "description": "Virtual address of the first instruction" },
+      "file_offset": { "type": ["integer", "null"], "minimum": 0, "description": "Offset of the first instruction in the file, null when it is not in a scanned section" },
+      "instructions": { "type": "string", "description": "Formatted instructions, eg. `pop rdi; ret;`" },
+      "instruction_count": { "type": "integer", "minimum": 1 },
+      "is_stack_pivot": { "type": "boolean" },
+      "is_base_pivot": { "type": "boolean" }
+    },
+    "required": ["address", "file_offset", "instructions", "instruction_count", "is_stack_pivot", "is_base_pivot"],
+    "additionalProperties": false
+  }
+}"#;
+
+/// Kernel symbols `--magic` prints by default
+const MAGIC_SYMBOLS: [&str; 8] = [
+    "modprobe_path",
+    "core_pattern",
+    "init_cred",
+    "prepare_kernel_cred",
+    "commit_creds",
+    "find_task_by_vpid",
+    "init_nsproxy",
+    "switch_task_namespaces",
+];
+
+/// The offset of a `--magic` symbol or `--c-header` gadget from the base, those below the base are
+/// negative
+fn magic_offset(addr: u64, base: u64) -> String {
+	match addr.checked_sub(base) {
+		Some(offset) => format!("{:#x}", offset),
+		None => format!("-{:#x}", base - addr),
+	}
+}
+
+/// Explains a failed symbol lookup in a stripped binary whose debug file is missing
+fn warn_missing_debuglink(b: &Binary) {
+	if let Some(debuglink) = b.missing_debuglink() {
+		eprintln!(
+			"warning: binary is stripped and its debug file `{}` could not be found or doesn't match its CRC, symbols will not be resolved",
+			debuglink
+		);
+	}
+}
+
+/// The `--magic` line for a symbol at `addrs`, `None` when it wasn't found
+fn magic_define(sym: &str, addrs: &[u64], base: u64, ifunc: bool) -> Option<String> {
+    let (addr, others) = addrs.split_first()?;
+    // Duplicates such as local symbols sharing a name are listed after the first match
+    let also = match others {
+        [] => String::new(),
+        _ => {
+            let others = others.iter().map(|other| magic_offset(*other, base)).collect::<Vec<_>>();
+            format!(" /* also {} */", others.join(", "))
         }
+    };
+    let resolver = if ifunc { " // ifunc resolver" } else { "" };
+    let offset = magic_offset(*addr, base);
+    Some(format!("#define {:<24} {}{}{}", sym.to_uppercase(), offset, also, resolver))
+}
 
-        // replace address of thunks/jump_thunks/call_thunks with the String in the vec
-        let replace_thunk_addresses = |thunks: &Vec<(String, Option<u64>)>, formatted: &mut String| {
-            for (name, address) in thunks {
-                if let Some(addr) = address {
-                    *formatted = formatted.replace(
-                        &format!("{addr:#x}"),
-                        &format!("{addr:#x} <{name}>")
-                    );
-                }
-            }
-        };
-
-        // Replace addresses of thunks, jump_thunks, and call_thunks with their names
-        replace_thunk_addresses(thunks, &mut formatted);
-        replace_thunk_addresses(jump_thunks, &mut formatted);
-        replace_thunk_addresses(call_thunks, &mut formatted);
-
-        if !sort {
-            output.write(&format!("{:#010x}: ", address), FormatterTextKind::Function);
-            output.write(&formatted, FormatterTextKind::Text);
-            match writeln!(w, "{}", output) {
-                Ok(_) => (),
-                Err(_) => return, // Pipe closed - finished writing gadgets
-            }
-        } else {
-            formatted_gadgets.push((address.clone(), formatted));
+fn print_magic(bin: &Binary, syms: &[String], base_sym: &str) {
+    let base = match bin.get_sym_addr(base_sym) {
+        Some(base) => base,
+        None => {
+            eprintln!("warning: base symbol `{}` not found, printing absolute addresses", base_sym);
+            0
         }
-	}
+    };
 
-    if sort {
-        formatted_gadgets.sort_by(|(_, gadget1), (_, gadget2)| gadget1.cmp(gadget2));
-        for (address, formatted) in formatted_gadgets {
-            output.clear();
-            output.write(&format!("{:#010x}: ", address), FormatterTextKind::Function);
-            output.write(&formatted, FormatterTextKind::Text);
-            match writeln!(w, "{}", output) {
-                Ok(_) => (),
-                Err(_) => return, // Pipe closed - finished writing gadgets
+    let mut missing = Vec::new();
+    for sym in syms.iter().map(String::as_str) {
+        let addrs = bin.get_all_sym_addrs(sym);
+        if addrs.is_empty() {
+            missing.push(sym);
+        }
+        let ifunc = bin.is_ifunc(sym);
+        if let Some(line) = magic_define(sym, &addrs, base, ifunc) {
+            if ifunc {
+                eprintln!("warning: `{}` is an ifunc, its address is the resolver rather than the implementation", sym);
             }
+            println!("{}", line);
         }
     }
+    if !missing.is_empty() {
+        eprintln!("warning: symbols not found: {}", missing.join(", "));
+    }
+    if !missing.is_empty() || bin.get_sym_addr(base_sym).is_none() {
+        warn_missing_debuglink(bin);
+    }
 }
 
-fn print_magic(bin: &Binary) {
-    let base = bin.get_sym_addr("_text").unwrap_or(0);
-
-    let syms = [
-        "modprobe_path",
-        "core_pattern",
-        "init_cred",
-        "prepare_kernel_cred",
-        "commit_creds",
-        "find_task_by_vpid",
-        "init_nsproxy",
-        "switch_task_namespaces",
-    ];
+/// Prints how often each form of indirect branch appears as a JOP/COP tail, most common first
+fn print_branch_targets(sections: &[Section], noisy: bool, hex_style: HexStyle) {
+	let mut counts = FxHashMap::default();
+	for dis in sections.iter().filter_map(Disassembly::new) {
+		for offset in 0..dis.bytes().len() {
+			let instr = match dis.instruction(offset) {
+				Some(instr) => *instr,
+				None => continue,
+			};
+			let rule = gadget_tail_rule(&instr, false, false, false, false, false, true, true, false, noisy, None, &[], &[], &[]);
+			if !matches!(rule, Some(TailRule::JopJmp | TailRule::JopCall)) {
+				continue;
+			}
+			let mut form = ColourFormatter::new();
+			format_instructions(&[instr], &mut form, hex_style, false);
+			let form = form.plain().trim_end_matches(';').to_string();
+			*counts.entry(form).or_insert(0usize) += 1;
+		}
+	}
+	let mut counts = counts.into_iter().collect::<Vec<_>>();
+	counts.sort_unstable_by(|(a_form, a_count), (b_form, b_count)| {
+		b_count.cmp(a_count).then_with(|| a_form.cmp(b_form))
+	});
+	for (form, count) in counts {
+		println!("{:>8}  {}", count, form);
+	}
+}
 
-    for sym in syms {
-        if let Some(addr) = bin.get_sym_addr(sym) {
-            println!("#define {:<24} {:#x}", sym.to_uppercase(), addr-base);
-        }
-    }
+/// Converts an error from `run` into its JSON report, errors raised by the command line tool itself
+/// are invalid arguments
+fn error_report(e: &(dyn Error + 'static)) -> ErrorReport {
+	if let Some(e) = e.downcast_ref::<ropr::error::Error>() {
+		return e.into();
+	}
+	if let Some(e) = e.downcast_ref::<std::io::Error>() {
+		return e.into();
+	}
+	let kind = if e.is::<rmp_serde::decode::Error>() || e.is::<rmp_serde::encode::Error>() {
+		"gadget-dump"
+	}
+	else {
+		"invalid-argument"
+	};
+	ErrorReport {
+		error: e.to_string(),
+		kind,
+	}
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
+	let opts = Opt::parse();
+	let error_format = opts.error_format;
+	match run(opts) {
+		Err(e) if error_format == ErrorFormat::Json => {
+			eprintln!("{}", serde_json::to_string(&error_report(e.as_ref()))?);
+			std::process::exit(1)
+		}
+		result => result,
+	}
+}
+
+fn run(opts: Opt) -> Result<(), Box<dyn Error>> {
 	let start = Instant::now();
 
-	let opts = Opt::parse();
+	if opts.json_schema {
+		println!("{}", GADGET_JSON_SCHEMA);
+		return Ok(());
+	}
 
-	let b = opts.binary;
-	let b = Binary::new(b)?;
-	let sections = b.sections(opts.raw)?;
+	let mut b = match &opts.binary {
+		Some(b) if b.as_os_str() == "-" => {
+			if opts.merge_stdin {
+				return Err("`--merge-stdin` can't be used when reading the binary from stdin".into());
+			}
+			Binary::from_reader(stdin().lock())?
+		}
+		Some(b) => Binary::new(b)?,
+		None => return Err("no binary given".into()),
+	};
+	if let Some(path) = &opts.symbols_from {
+		b.symbols_from(path)?;
+	}
+	if opts.patch_alternatives != Some(false) && opts.raw != Some(true) {
+		b.apply_alternatives();
+	}
+	let output_file = opts.output.as_ref().map(File::create).transpose()?;
+	if let Some(compression) = b.packed_kernel() {
+		eprintln!(
+			"warning: could not extract vmlinux from the {} compressed bzImage, scanning the file as it is",
+			compression
+		);
+	}
+	let sections = b.sections(opts.raw, opts.bitness, &opts.section)?;
+	if let Some(name) = opts
+		.section
+		.iter()
+		.find(|name| *name != "all-exec" && !sections.iter().any(|section| section.name() == *name))
+	{
+		return Err(format!("no section named `{}` to scan", name).into());
+	}
+	for section in sections.iter().filter(|section| section.truncated()) {
+		eprintln!(
+			"warning: section `{}` extends past the end of the file and has been truncated",
+			section.name()
+		);
+	}
 
-	let noisy = opts.noisy;
+	let noisy = opts.noisy || opts.call_import.is_some();
 	let colour = opts.colour;
 	let rop = !opts.norop;
 	let sys = !opts.nosys;
+	let iret = sys && !opts.no_iret;
+	let sysret = sys && !opts.no_sysret;
+	let sysexit = sys && !opts.no_sysexit;
+	let syscall = !opts.nosyscall;
 	let jop = !opts.nojop;
+	// Call ended gadgets follow `--nojop` unless chosen explicitly
+	let cop = opts.cop || (jop && !opts.nocop);
 	let uniq = !opts.nouniq;
-	let sort = opts.sort;
-	let magic = opts.magic;
+	let sort = if opts.sort_length { SortKey::Length } else { opts.sort };
+	let hex_style = opts.hex_style;
+	let preview = opts.preview;
+	let per_tail_limit = opts.per_tail_limit;
+	let prune_heads = opts.prune_heads;
+	let no_embedded_ret = opts.no_embedded_ret;
+	let no_orphan_prefix = opts.no_orphan_prefix;
+	let endbr = opts.endbr;
+	let no_ret_imm = opts.no_ret_imm;
+	let direct_branch_tails = opts.direct_branch_tails;
+	let branch_symbols = match direct_branch_tails {
+		true => b.function_symbols().into_iter().collect::<FxHashMap<_, _>>(),
+		false => FxHashMap::default(),
+	};
+	let scan_chunk = opts.scan_chunk;
+	if scan_chunk == 0 {
+		return Err("scan chunk must be >0".into());
+	}
+	let section_relative = opts.section_relative;
+	let magic = opts.magic || !opts.magic_syms.is_empty() || opts.magic_file.is_some();
 	let stack_pivot = opts.stack_pivot;
 	let base_pivot = opts.base_pivot;
+	let load_from_stack = opts.load_from_stack;
+	let leak_rsp = opts.leak_rsp;
+	let no_cmov = opts.no_cmov;
+	let popad = opts.popad;
+	let dispatcher = opts.dispatcher;
+	let vtable_gadgets = opts.vtable_gadgets;
+	let prologue_only = opts.prologue_only;
+	let string_ops = opts.string_ops;
+	let max_regs = opts.max_regs;
+	let max_bytes = opts.max_bytes;
+	let min_stack_delta = opts.min_stack_delta;
+	let max_stack_delta = opts.max_stack_delta;
+	let set_reg = match opts.set.as_deref() {
+		None => None,
+		Some(name) => match parse_register(name) {
+			Some(reg) => Some(reg),
+			None => return Err(format!("unknown register `{}`", name).into()),
+		},
+	};
+	let avoid_regs = opts
+		.avoid_regs
+		.iter()
+		.map(|name| parse_register(name).ok_or_else(|| format!("unknown register `{}`", name)))
+		.collect::<Result<Vec<_>, _>>()?;
+	let scaled_write = opts.scaled_write;
+	let mem_copy = opts.mem_copy;
+	let writes_mem = opts.writes_mem;
+	let idempotent = opts.idempotent;
+	let write_gadget_filter = opts.write_gadgets || scaled_write;
+	let import_slots = match &opts.call_import {
+		Some(name) => {
+			let slots = b
+				.got_imports()
+				.into_iter()
+				.filter(|(_, import)| import == name)
+				.map(|(slot, _)| slot)
+				.collect::<Vec<_>>();
+			if slots.is_empty() {
+				return Err(format!("no GOT entry found for import `{}`", name).into());
+			}
+			if b.is_ifunc(name) {
+				eprintln!(
+					"warning: `{}` is an ifunc, the implementation its GOT entry resolves to is chosen at load time",
+					name
+				);
+			}
+			Some(slots)
+		}
+		None => None,
+	};
+	let push_reg = match opts.push_reg.as_deref() {
+		None => None,
+		Some("any") => Some(None),
+		Some(name) => match parse_register(name) {
+			Some(reg) => Some(Some(reg.full_register())),
+			None => return Err(format!("unknown register `{}`", name).into()),
+		},
+	};
+	let bitop = match opts.bitop.as_deref() {
+		None => None,
+		Some(name) => match parse_register(name) {
+			Some(reg) => Some(reg.full_register()),
+			None => return Err(format!("unknown register `{}`", name).into()),
+		},
+	};
+	let head_mnemonics = opts
+		.head_mnemonic
+		.iter()
+		.map(|name| parse_mnemonic(name).ok_or_else(|| format!("unknown mnemonic `{}`", name)))
+		.collect::<Result<Vec<_>, _>>()?;
+	let syscall_toolkit = opts.syscall_toolkit;
 	let max_instructions_per_gadget = opts.max_instr as usize;
 
     if magic {
-        print_magic(&b);
+        let mut syms = opts.magic_syms.clone();
+        if let Some(path) = &opts.magic_file {
+            let file = std::fs::read_to_string(path)?;
+            syms.extend(file.lines().map(str::trim).filter(|l| !l.is_empty()).map(String::from));
+        }
+        if syms.is_empty() {
+            syms = MAGIC_SYMBOLS.iter().map(|sym| sym.to_string()).collect();
+        }
+        print_magic(&b, &syms, &opts.magic_base);
         return Ok(());
     }
 
@@ -200,22 +1561,33 @@ fn main() -> Result<(), Box<dyn Error>> {
 		panic!("Max instructions must be >0");
 	}
 
-	let ranges = opts
-		.range
-		.iter()
-		.filter_map(|s| s.split_once('-'))
-		.filter_map(|(mut from, mut to)| {
-			if from.starts_with("0x") {
-				from = &from[2..];
-			}
-			if to.starts_with("0x") {
-				to = &to[2..];
-			}
-			let from = usize::from_str_radix(from, 16).ok()?;
-			let to = usize::from_str_radix(to, 16).ok()?;
-			Some((from, to))
-		})
-		.collect::<Vec<_>>();
+	let ranges = parse_ranges(&opts.range)?;
+	let exclude_ranges = parse_ranges(&opts.exclude_range)?;
+	let bad_bytes = parse_bad_bytes(&opts.bad_bytes)?;
+	let base = match opts.base.as_deref() {
+		None => 0,
+		Some(base) => match usize::from_str_radix(base.trim_start_matches("0x"), 16) {
+			Ok(base) => base,
+			Err(_) => match b.get_sym_addr(base) {
+				Some(addr) => addr as usize,
+				None => {
+					warn_missing_debuglink(&b);
+					return Err(format!("unknown base symbol `{}`", base).into());
+				}
+			},
+		},
+	};
+
+	for (from, to) in ranges.iter().filter(|(from, to)| from <= to) {
+		let overlaps = sections.iter().any(|section| {
+			let start = section.program_base() + section.section_vaddr();
+			let end = start + section.bytes().len();
+			*from < end && start <= *to
+		});
+		if !overlaps {
+			return Err(format!("range {:#x}-{:#x} does not overlap any scanned section", from, to).into());
+		}
+	}
 
 	let regices = opts
 		.regex
@@ -246,59 +1618,407 @@ fn main() -> Result<(), Box<dyn Error>> {
         .collect();
 
 
-    let ret_thunk = b.get_sym_addr("__x86_return_thunk");
+    let mut ret_thunk = b.get_sym_addr("__x86_return_thunk");
+	if ret_thunk.is_none() && opts.detect_return_thunk {
+		ret_thunk = detect_return_thunk(&sections);
+		match ret_thunk {
+			Some(addr) => eprintln!("==> Detected return thunk at {:#x}", addr),
+			None => eprintln!("warning: no return thunk could be detected"),
+		}
+	}
     //panic!("{}", ret_thunk.unwrap());
 
-	let gadget_to_addr = sections
-		.iter()
-		.filter_map(Disassembly::new)
-		.flat_map(|dis| {
-			(0..dis.bytes().len())
-				.into_par_iter()
-				.filter(|offset| dis.is_tail_at(*offset, rop, sys, jop, noisy, ret_thunk, &thunks, &jump_thunks, &call_thunks))
-				.flat_map_iter(|tail| {
-					dis.gadgets_from_tail(tail, max_instructions_per_gadget, noisy, uniq)
-				})
-				.collect::<Vec<_>>()
-		})
-		.filter(|&(_, address)| {
-			if ranges.is_empty() {
-				return true;
+	if opts.dry_run {
+		println!("sections:");
+		print_sections(&sections);
+		println!("ranges:");
+		for (from, to) in &ranges {
+			println!("  {:#x}-{:#x}", from, to);
+		}
+		println!("excluded ranges:");
+		for (from, to) in &exclude_ranges {
+			println!("  {:#x}-{:#x}", from, to);
+		}
+		let bad_bytes = bad_bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>();
+		println!("bad bytes: {}", bad_bytes.join(","));
+		println!("base: {:#x}", base);
+		println!("regexes:");
+		for regex in &regices {
+			println!("  {}", regex);
+		}
+		println!("inverse regexes:");
+		for regex in &regices_inverse {
+			println!("  {}", regex);
+		}
+		println!("return thunk: {}", ret_thunk.map_or_else(|| String::from("none"), |addr| format!("{:#x}", addr)));
+		let resolved = thunks.iter().chain(&jump_thunks).chain(&call_thunks).filter(|(_, addr)| addr.is_some()).count();
+		println!("indirect thunks resolved: {}", resolved);
+		println!("settings:");
+		let settings = [
+			("rop", rop),
+			("iret", iret),
+			("sysret", sysret),
+			("sysexit", sysexit),
+			("syscall", syscall),
+			("jop", jop),
+			("cop", cop),
+			("direct_branch_tails", direct_branch_tails),
+			("noisy", noisy),
+			("uniq", uniq),
+			("unique_text", opts.unique_text),
+			("patch_alternatives", opts.patch_alternatives != Some(false)),
+			("prune_heads", prune_heads),
+			("no_embedded_ret", no_embedded_ret),
+			("no_orphan_prefix", no_orphan_prefix),
+			("endbr", endbr),
+			("no_ret_imm", no_ret_imm),
+			("stack_pivot", stack_pivot),
+			("base_pivot", base_pivot),
+			("load_from_stack", load_from_stack),
+			("no_cmov", no_cmov),
+			("popad", popad),
+			("leak_rsp", leak_rsp),
+			("dispatcher", dispatcher),
+			("vtable_gadgets", vtable_gadgets),
+			("prologue_only", prologue_only),
+			("string_ops", string_ops),
+			("write_gadgets", write_gadget_filter),
+			("scaled_write", scaled_write),
+			("mem_copy", mem_copy),
+			("writes_mem", writes_mem),
+			("idempotent", idempotent),
+			("head_mnemonic", !head_mnemonics.is_empty()),
+			("avoid_regs", !avoid_regs.is_empty()),
+			("max_bytes", max_bytes.is_some()),
+			("set", set_reg.is_some()),
+			("stack_delta", min_stack_delta.is_some() || max_stack_delta.is_some()),
+			("push_reg", push_reg.is_some()),
+			("bitop", bitop.is_some()),
+			("syscall_toolkit", syscall_toolkit),
+			("syscall_num", opts.syscall_num),
+			("json", opts.json),
+			("count", opts.count),
+			("report", opts.report),
+			("catalog", opts.catalog),
+			("c_header", opts.c_header || opts.c_header_relative),
+			("pwn", opts.pwn),
+			("section_relative", section_relative),
+			("tag_tail_rule", opts.tag_tail_rule),
+			("bb_context", opts.bb_context),
+			("show_tail_addr", opts.show_tail_addr),
+			("describe", opts.describe),
+			("merge_stdin", opts.merge_stdin),
+			("verify", opts.verify),
+			("shortest", opts.shortest),
+		];
+		for (name, value) in settings {
+			println!("  {:<16} {}", name, value);
+		}
+		println!("  {:<16} {}", "max_instr", max_instructions_per_gadget);
+		println!("  {:<16} {}", "scan_chunk", scan_chunk);
+		return Ok(());
+	}
+
+	if opts.branch_targets {
+		print_branch_targets(&sections, noisy, hex_style);
+		return Ok(());
+	}
+
+	// The gadget filters, also applied while scanning so that `--preview` counts matching gadgets
+	let keep = |g: &Gadget| {
+		let writes_match = (!scaled_write && !write_gadget_filter)
+			|| match g.memory_write() {
+				Some(write) => !scaled_write || write.index != Register::None,
+				None => !write_gadget_filter,
+			};
+		let stack_delta_matches = (min_stack_delta.is_none() && max_stack_delta.is_none())
+			|| g.stack_delta().is_some_and(|delta| {
+				min_stack_delta.is_none_or(|min| delta >= min)
+					&& max_stack_delta.is_none_or(|max| delta <= max)
+			});
+		(!no_cmov || !g.has_cmov())
+			&& (!popad || g.restored_registers(MIN_RESTORE_POPS, ret_thunk).is_some())
+			&& (!stack_pivot || g.is_stack_pivot(ret_thunk))
+			&& (!base_pivot || g.is_base_pivot())
+			&& (!load_from_stack || g.load_from_stack(ret_thunk).is_some())
+			&& (!leak_rsp || g.leaks_rsp(ret_thunk).is_some())
+			&& (!dispatcher || g.is_dispatcher())
+			&& (!vtable_gadgets || g.vtable_load().is_some())
+			&& (!prologue_only || g.starts_with_prologue())
+			&& (!string_ops || g.string_op_registers().is_some())
+			&& writes_match
+			&& bitop.is_none_or(|reg| g.bit_op(reg).is_some())
+			&& (!mem_copy || g.memory_copy().is_some())
+			&& (!writes_mem || g.writes_memory())
+			&& (!idempotent || g.is_idempotent())
+			&& (!no_ret_imm || g.ret_immediate().is_none())
+			&& (head_mnemonics.is_empty()
+				|| g.instructions().first().is_some_and(|i| head_mnemonics.contains(&i.mnemonic())))
+			&& max_regs.is_none_or(|max| g.register_count() <= max)
+			&& max_bytes.is_none_or(|max| g.byte_len() <= max)
+			&& (avoid_regs.is_empty() || !g.clobbers(&avoid_regs))
+			&& set_reg.is_none_or(|reg| g.sets_register(reg))
+			&& stack_delta_matches
+			&& import_slots
+				.as_ref()
+				.is_none_or(|slots| g.branch_slot().is_some_and(|slot| slots.contains(&slot)))
+			&& push_reg.is_none_or(|reg| {
+				g.pushed_register(ret_thunk)
+					.is_some_and(|pushed| reg.is_none_or(|reg| pushed.full_register() == reg))
+			})
+			// Formatting is the most expensive check so it only runs on gadgets every other
+			// filter kept
+			&& ((regices.is_empty() && regices_inverse.is_empty()) || {
+				let mut formatted = String::new();
+				g.format_instruction_styled(&mut formatted, hex_style);
+				regices.iter().all(|r| r.is_match(&formatted))
+					&& !regices_inverse.iter().any(|r| r.is_match(&formatted))
+			})
+	};
+
+	// Preview mode stops scanning as soon as enough gadgets have been found
+	let found = AtomicUsize::new(0);
+	let previewed = Mutex::new(FxHashSet::default());
+	let scanning = || preview.is_none_or(|n| found.load(Ordering::Relaxed) < n);
+
+	let mut scanned = match &opts.load_bin {
+		Some(path) => load_gadgets(path, &b, uniq)?,
+		None => sections
+			.iter()
+			.filter(|_| scanning())
+			.filter_map(Disassembly::new)
+			.flat_map(|dis| {
+				let operand_bytes = no_embedded_ret.then(|| dis.linear_operand_bytes());
+				let linear_starts = no_orphan_prefix.then(|| dis.linear_instruction_starts());
+				let len = dis.bytes().len();
+				// Each work item scans a contiguous chunk of offsets, keeping its cached instructions close
+				(0..len.div_ceil(scan_chunk))
+					.into_par_iter()
+					.flat_map_iter(move |chunk| chunk * scan_chunk..len.min((chunk + 1) * scan_chunk))
+					.filter(|_| scanning())
+					.filter(move |offset| operand_bytes.as_ref().is_none_or(|operand| !operand[*offset]))
+					.filter(|offset| dis.is_tail_at(*offset, rop, iret, sysret, sysexit, syscall, jop, cop, direct_branch_tails, noisy, ret_thunk, &thunks, &jump_thunks, &call_thunks))
+					.flat_map_iter(|tail| {
+						let gadgets = dis.gadgets_from_tail(tail, max_instructions_per_gadget, noisy, uniq);
+						let gadgets = match prune_heads {
+							true => gadgets.pruned().collect::<Vec<_>>(),
+							false => gadgets.collect::<Vec<_>>(),
+						};
+						let gadgets = match &linear_starts {
+							Some(starts) => gadgets
+								.into_iter()
+								.filter(|(_, address)| !dis.has_orphan_prefix(starts, address - dis.file_offset()))
+								.collect(),
+							None => gadgets,
+						};
+						let gadgets = match endbr {
+							true => gadgets
+								.into_iter()
+								.filter(|(g, address)| {
+									!g.ends_in_branch(ret_thunk) || dis.at_landing_pad(address - dis.file_offset())
+								})
+								.collect(),
+							false => gadgets,
+						};
+						// Gadgets are produced longest first, keep the shortest when limited
+						let skip = per_tail_limit.map_or(0, |n| gadgets.len().saturating_sub(n));
+						gadgets.into_iter().skip(skip)
+					})
+					.filter(|(_, address)| in_ranges(*address, &ranges, &exclude_ranges))
+					.filter(|(_, address)| {
+						bad_bytes.is_empty() || !has_bad_byte(*address, &sections, &bad_bytes)
+					})
+					// Only distinct gadgets which will be printed count towards the preview
+					.filter(|(g, _)| {
+						let distinct = || previewed.lock().unwrap().insert(g.clone());
+						preview.is_none() || (keep(g) && distinct())
+					})
+					.inspect(|_| {
+						found.fetch_add(1, Ordering::Relaxed);
+					})
+					.collect::<Vec<_>>()
+			})
+			.collect::<Vec<_>>(),
+	};
+	if let Some(n) = preview {
+		scanned.truncate(n);
+	}
+
+	if opts.merge_stdin {
+		for (number, line) in stdin().lock().lines().enumerate() {
+			let line = line?;
+			if line.trim().is_empty() {
+				continue;
 			}
-			ranges
+			// Addresses printed with `--section-relative` or below `--base` can't be decoded
+			let (address, instructions) = match parse_gadget_line(&line) {
+				Some(parsed) => parsed,
+				None => {
+					eprintln!(
+						"warning: skipping stdin line {}, expected an address and instructions \
+						 eg. `0x1234: pop rdi; ret;`",
+						number + 1
+					);
+					continue;
+				}
+			};
+			let gadget = sections
 				.iter()
-				.any(|(from, to)| -> bool { *from <= address && address <= *to })
-		})
+				.find_map(|section| decode_gadget(section, address, instructions.len(), uniq));
+			let (gadget, address) = match gadget {
+				Some(gadget) => gadget,
+				None => {
+					eprintln!(
+						"warning: skipping stdin line {}, no gadget could be decoded at {:#x}",
+						number + 1,
+						address
+					);
+					continue;
+				}
+			};
+			// Lines from a different build, or with addresses relative to a `--base`, decode to
+			// other instructions
+			let decoded = gadget.instructions();
+			if let Some(index) = merged_line_difference(&instructions, decoded, hex_style) {
+				let original = instructions.iter().map(|i| format!("{};", i)).collect::<Vec<_>>();
+				let original = original.join(" ");
+				print_mismatch("merge-stdin", address, &original, decoded, index, hex_style);
+				eprintln!(
+					"warning: skipping stdin line {}, its instructions don't match the binary",
+					number + 1
+				);
+				continue;
+			}
+			scanned.push((gadget, address));
+		}
+	}
+
+	if let Some(path) = &opts.dump_bin {
+		dump_gadgets(path, &b, &scanned)?;
+	}
+
+	let gadget_to_addr = scanned
+		.into_iter()
+		.filter(|&(_, address)| in_ranges(address, &ranges, &exclude_ranges))
+		.filter(|&(_, address)| bad_bytes.is_empty() || !has_bad_byte(address, &sections, &bad_bytes))
 		.collect::<FxHashMap<_, _>>();
 
 	let mut gadgets = gadget_to_addr
 		.into_iter()
-		.filter(|(g, _)| {
-			let mut formatted = String::new();
-			g.format_instruction(&mut formatted);
-			regices.iter().all(|r| r.is_match(&formatted))
-				&& !regices_inverse.iter().any(|r| r.is_match(&formatted))
-		})
-		.filter(|(g, _)| !stack_pivot | g.is_stack_pivot(ret_thunk))
-		.filter(|(g, _)| !base_pivot | g.is_base_pivot())
+		.filter(|(g, _)| keep(g))
 		.collect::<Vec<_>>();
-	gadgets.sort_unstable_by(|(_, addr1), (_, addr2)| addr1.cmp(addr2));
-
+	gadgets.sort_unstable_by_key(|(_, addr)| *addr);
+	if opts.unique_text {
+		// Gadgets are in address order so the first of each text kept is the lowest
+		let mut seen = FxHashSet::default();
+		gadgets.retain(|(g, _)| {
+			let mut formatted = String::new();
+			g.format_instruction_styled(&mut formatted, hex_style);
+			seen.insert(formatted)
+		});
+	}
+	if opts.shortest {
+		gadgets = shortest_gadget(gadgets).into_iter().collect();
+	}
+	if opts.verify {
+		let mismatches = verify_gadgets(&gadgets, &sections, hex_style);
+		if mismatches > 0 {
+			eprintln!("warning: {} gadgets did not match the binary's bytes", mismatches);
+		}
+	}
 	let gadget_count = gadgets.len();
+	if opts.count {
+		println!("{}", gadget_count);
+		return Ok(());
+	}
+
+	sort_gadgets(&mut gadgets, sort, ret_thunk);
 
 	// Don't account for time it takes to print gadgets since this depends on terminal implementation
 	let elapsed = Instant::now() - start;
 
 	// Stdout uses a LineWriter internally, therefore we improve performance by wrapping stdout in a BufWriter
-	let mut stdout = BufWriter::new(stdout());
+	let mut sinks = Vec::new();
+	if output_file.is_none() || opts.tee {
+		sinks.push(Sink::new(BufWriter::new(stdout()), true));
+	}
+	if let Some(file) = output_file {
+		sinks.push(Sink::new(BufWriter::new(file), colour == Some(true) && !opts.json));
+	}
 
-	if let Some(colour) = colour {
+	if opts.json {
+		set_override(false);
+	}
+	else if let Some(colour) = colour {
 		set_override(colour);
 	}
 
-	write_gadgets(&mut stdout, &gadgets, ret_thunk, &thunks, &jump_thunks, &call_thunks, sort);
+	if let Some(soname) = b.soname() {
+		eprintln!("==> {} ({})", soname, b.path().display());
+	}
 
-	drop(stdout);
+	if opts.symbols {
+		warn_missing_debuglink(&b);
+	}
+	let symbol_index = opts.symbols.then(|| b.symbol_index());
+	let output_options = OutputOptions {
+		ret_thunk,
+		thunks: &thunks,
+		jump_thunks: &jump_thunks,
+		call_thunks: &call_thunks,
+		sort: sort == SortKey::Text,
+		hex_style,
+		sections: &sections,
+		section_relative,
+		bb_context: opts.bb_context,
+		tag_pivot: stack_pivot,
+		show_tail_addr: opts.show_tail_addr,
+		tag_restored: popad,
+		resolve_rip: opts.resolve_rip,
+		describe: opts.describe,
+		symbols: symbol_index.as_ref(),
+		base,
+		branch_symbols: direct_branch_tails.then_some(&branch_symbols),
+		tag_tail_rule: opts.tag_tail_rule,
+		noisy,
+		json: opts.json,
+	};
+
+	if opts.report {
+		write_report(&mut sinks, &gadgets, &output_options);
+	}
+	else if opts.catalog {
+		write_catalog(&mut sinks, &gadgets, &output_options);
+	}
+	else if opts.c_header || opts.c_header_relative {
+		// Relative to the kernel's `_text` like `--magic`, otherwise to the lowest scanned section
+		let base = match opts.c_header_relative {
+			true => b.get_sym_addr("_text").map(|addr| addr as usize).unwrap_or_else(|| {
+				warn_missing_debuglink(&b);
+				sections
+					.iter()
+					.map(|section| section.program_base() + section.section_vaddr())
+					.min()
+					.unwrap_or(0)
+			}),
+			false => 0,
+		};
+		write_c_header(&mut sinks, &gadgets, &output_options, base);
+	}
+	else if opts.pwn {
+		write_pwn(&mut sinks, &gadgets, &output_options);
+	}
+	else if syscall_toolkit {
+		write_syscall_toolkit(&mut sinks, &gadgets, &output_options);
+	}
+	else if opts.syscall_num {
+		write_syscall_numbers(&mut sinks, &gadgets, &output_options);
+	}
+	else {
+		write_gadgets(&mut sinks, &gadgets, &output_options);
+	}
+
+	drop(sinks);
 
 	eprintln!(
 		"\n==> Found {} gadgets in {:.3} seconds",
@@ -308,3 +2028,153 @@ fn main() -> Result<(), Box<dyn Error>> {
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Gadgets of raw 64-bit code with their addresses
+	fn scan(bytes: &[u8]) -> Vec<(Gadget, usize)> {
+		let binary = Binary::from_reader(bytes).unwrap();
+		let sections = binary.sections(Some(true), Some(Bitness::Bits64), &[]).unwrap();
+		let dis = Disassembly::new(&sections[0]).unwrap();
+		dis.gadget_iter(6, false, false).collect()
+	}
+
+	#[test]
+	fn shortest_gadget_breaks_ties_by_address() {
+		// pop rdi; ret; pop rdi; pop rsi; ret; pop rdi; ret
+		let gadgets = scan(&[0x5f, 0xc3, 0x5f, 0x5e, 0xc3, 0x5f, 0xc3])
+			.into_iter()
+			.filter(|(g, _)| g.instructions()[0].mnemonic() == Mnemonic::Pop)
+			.collect::<Vec<_>>();
+		let (gadget, address) = shortest_gadget(gadgets).unwrap();
+		assert_eq!((gadget.instructions().len(), address), (2, 0));
+		assert!(shortest_gadget(Vec::new()).is_none());
+	}
+
+	#[test]
+	fn merged_lines_must_match_the_binary() {
+		// pop rdi; ret
+		let (gadget, _) = scan(&[0x5f, 0xc3]).into_iter().find(|(_, a)| *a == 0).unwrap();
+		let decoded = gadget.instructions();
+		let difference = |line| {
+			let (_, instructions) = parse_gadget_line(line).unwrap();
+			merged_line_difference(&instructions, decoded, HexStyle::Lower)
+		};
+		assert_eq!(difference("0x0: pop rdi; ret;"), None);
+		assert_eq!(difference("0x0: pop rdi; ret <__x86_return_thunk>;"), None);
+		assert_eq!(difference("0x0: pop rsi; ret;"), Some(0));
+		assert_eq!(difference("0x0: pop rdi; ret; nop;"), Some(2));
+	}
+
+	#[test]
+	fn magic_offsets_below_the_base_are_negative() {
+		assert_eq!(magic_offset(0x1234, 0x1000), "0x234");
+		assert_eq!(magic_offset(0x1000, 0x1000), "0x0");
+		assert_eq!(magic_offset(0xf00, 0x1000), "-0x100");
+	}
+
+	#[test]
+	fn magic_lists_duplicate_addresses() {
+		let define = |addrs: &[u64], ifunc| magic_define("commit_creds", addrs, 0x1000, ifunc);
+		assert_eq!(define(&[], false), None);
+		assert_eq!(define(&[0x1100], false).unwrap(), "#define COMMIT_CREDS             0x100");
+		assert_eq!(
+			define(&[0x1100, 0x1200, 0xf00], false).unwrap(),
+			"#define COMMIT_CREDS             0x100 /* also 0x200, -0x100 */"
+		);
+		assert_eq!(
+			define(&[0x1100, 0x1200], true).unwrap(),
+			"#define COMMIT_CREDS             0x100 /* also 0x200 */ // ifunc resolver"
+		);
+	}
+
+	#[test]
+	fn gadget_dumps_belong_to_their_binary() {
+		// pop rdi; ret; pop rdi; ret
+		let bytes = [0x5f, 0xc3, 0x5f, 0xc3];
+		let binary = Binary::from_reader(bytes.as_slice()).unwrap();
+		let path = std::env::temp_dir().join(format!("ropr-test-{}.bin", std::process::id()));
+		dump_gadgets(&path, &binary, &scan(&bytes)).unwrap();
+		// Gadgets are told apart by address only with `--nouniq`, however they were dumped
+		for (uniq, distinct) in [(true, 2), (false, 4)] {
+			let loaded = load_gadgets(&path, &binary, uniq).unwrap();
+			assert_eq!(loaded.len(), 4);
+			let loaded = loaded.into_iter().map(|(g, _)| g).collect::<FxHashSet<_>>();
+			assert_eq!(loaded.len(), distinct);
+		}
+		let other = Binary::from_reader([0x5e, 0xc3].as_slice()).unwrap();
+		assert!(load_gadgets(&path, &other, true).is_err());
+		std::fs::remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn sort_length_puts_shortest_first() {
+		// pop rdi; pop rsi; ret; pop rdi; ret
+		let mut gadgets = scan(&[0x5f, 0x5e, 0xc3, 0x5f, 0xc3]);
+		gadgets.sort_unstable_by_key(|(_, address)| *address);
+		sort_gadgets(&mut gadgets, SortKey::Length, None);
+		let order = gadgets
+			.iter()
+			.map(|(g, address)| (g.instructions().len(), *address))
+			.collect::<Vec<_>>();
+		assert_eq!(order, [(1, 2), (1, 4), (2, 1), (2, 3), (3, 0)]);
+	}
+
+	#[test]
+	fn sort_length_conflicts_with_sort() {
+		assert!(Opt::try_parse_from(["ropr", "--sort-length", "a.out"]).is_ok());
+		assert!(Opt::try_parse_from(["ropr", "--sort", "--sort-length", "a.out"]).is_err());
+		assert!(Opt::try_parse_from(["ropr", "--sort=length", "--sort-length", "a.out"]).is_err());
+	}
+
+	#[test]
+	fn parses_ranges() {
+		let ranges = [String::from("0x1000-0x2000"), String::from("3000-30ff")];
+		assert_eq!(parse_ranges(&ranges), Ok(vec![(0x1000, 0x2000), (0x3000, 0x30ff)]));
+		assert!(parse_ranges(&[String::from("foo")]).is_err());
+		assert!(parse_ranges(&[String::from("0x1000-")]).is_err());
+	}
+
+	#[test]
+	fn exclude_ranges_remove_from_include_ranges() {
+		let ranges = [(0x1000, 0x2000)];
+		let exclude_ranges = [(0x1800, 0x2800)];
+		assert!(in_ranges(0x1000, &ranges, &exclude_ranges));
+		assert!(in_ranges(0x17ff, &ranges, &exclude_ranges));
+		assert!(!in_ranges(0x1800, &ranges, &exclude_ranges));
+		assert!(!in_ranges(0x2000, &ranges, &exclude_ranges));
+		assert!(!in_ranges(0x2400, &ranges, &exclude_ranges));
+		// Without include ranges everything outside the excluded ones is kept
+		assert!(in_ranges(0x500, &[], &exclude_ranges));
+		assert!(!in_ranges(0x1800, &[], &exclude_ranges));
+		// Overlapping include ranges keep the union
+		assert!(in_ranges(0x2100, &[(0x1000, 0x2000), (0x1f00, 0x2200)], &[]));
+	}
+
+	#[test]
+	fn parses_stack_delta() {
+		assert_eq!(parse_stack_delta("24"), Ok(24));
+		assert_eq!(parse_stack_delta("0x18"), Ok(0x18));
+		assert_eq!(parse_stack_delta("-0x8"), Ok(-8));
+		assert!(parse_stack_delta("0x").is_err());
+		assert!(parse_stack_delta("ten").is_err());
+	}
+
+	#[test]
+	fn parses_bitness() {
+		assert!(matches!(parse_bitness("16"), Ok(Bitness::Bits16)));
+		assert!(matches!(parse_bitness("32"), Ok(Bitness::Bits32)));
+		assert!(matches!(parse_bitness("64"), Ok(Bitness::Bits64)));
+		assert!(parse_bitness("8").is_err());
+	}
+
+	#[test]
+	fn parses_bad_bytes() {
+		let bytes = ["00", "0x0a", " 20 "].map(String::from);
+		assert_eq!(parse_bad_bytes(&bytes), Ok(vec![0x00, 0x0a, 0x20]));
+		assert!(parse_bad_bytes(&[String::from("100")]).is_err());
+		assert!(parse_bad_bytes(&[String::from("zz")]).is_err());
+	}
+}
@@ -1,12 +1,62 @@
 use crate::error::{Error, Result};
-use goblin::{elf64::program_header::PF_X, pe::section_table::IMAGE_SCN_MEM_EXECUTE, Object};
+use flate2::{read::GzDecoder, Crc};
+use goblin::{
+	elf::{
+		note::NT_GNU_BUILD_ID,
+		section_header::{SHF_EXECINSTR, SHT_PROGBITS},
+		sym::{Sym, STT_FILE, STT_GNU_IFUNC, STT_SECTION},
+		Elf, ProgramHeader, SectionHeader,
+	},
+	elf64::program_header::{PF_X, PT_LOAD},
+	mach::{
+		constants::{
+			cputype::{CPU_TYPE_X86, CPU_TYPE_X86_64},
+			S_ATTR_PURE_INSTRUCTIONS, S_ATTR_SOME_INSTRUCTIONS,
+		},
+		Mach, MachO,
+	},
+	pe::section_table::IMAGE_SCN_MEM_EXECUTE,
+	Object,
+};
+use iced_x86::{Decoder, DecoderOptions, Encoder, OpKind};
 use std::{
 	fs::read,
+	io::Read,
 	path::{Path, PathBuf},
 };
+use xz2::read::XzDecoder;
+
+/// Symbols sorted by address, see `Binary::symbol_index`
+pub struct SymbolIndex {
+	/// Address, size and name
+	symbols: Vec<(u64, u64, String)>,
+}
+
+impl SymbolIndex {
+	/// Sorts `symbols` by address, at each address keeping a symbol with a size over any zero sized
+	/// label
+	fn new(mut symbols: Vec<(u64, u64, String)>) -> Self {
+		symbols.sort_unstable_by(|(a_addr, a_size, _), (b_addr, b_size, _)| {
+			a_addr.cmp(b_addr).then((*a_size == 0).cmp(&(*b_size == 0)))
+		});
+		symbols.dedup_by_key(|(address, ..)| *address);
+		Self { symbols }
+	}
+
+	/// The symbol with the greatest address at or below `address`, and the offset of `address` into it
+	///
+	/// `None` past the end of a sized symbol, only zero sized labels match without a bound
+	pub fn lookup(&self, address: u64) -> Option<(&str, u64)> {
+		let index = self.symbols.partition_point(|(start, ..)| *start <= address).checked_sub(1)?;
+		let (start, size, name) = &self.symbols[index];
+		let offset = address - start;
+		(*size == 0 || offset < *size).then_some((name, offset))
+	}
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum Bitness {
+	Bits16,
 	Bits32,
 	Bits64,
 }
@@ -14,45 +64,429 @@ pub enum Bitness {
 pub struct Binary {
 	path: PathBuf,
 	bytes: Vec<u8>,
+	debuglink: Option<String>,
+	debug: Option<Box<Binary>>,
 }
 
 impl Binary {
 	pub fn new(path: impl AsRef<Path>) -> Result<Self> {
+		let mut binary = Self::load(path)?;
+		binary.follow_debuglink();
+		Ok(binary)
+	}
+
+	/// Reads the whole binary from `reader`, eg. stdin, naming it `<stdin>`
+	pub fn from_reader(mut reader: impl Read) -> Result<Self> {
+		let mut bytes = Vec::new();
+		reader.read_to_end(&mut bytes)?;
+		let bytes = extract_vmlinux(&bytes).unwrap_or(bytes);
+		let mut binary = Self {
+			path: PathBuf::from("<stdin>"),
+			bytes,
+			debuglink: None,
+			debug: None,
+		};
+		binary.follow_debuglink();
+		Ok(binary)
+	}
+
+	fn load(path: impl AsRef<Path>) -> Result<Self> {
 		let path = path.as_ref();
 		let bytes = read(path)?;
+		let bytes = extract_vmlinux(&bytes).unwrap_or(bytes);
 		let path = path.to_path_buf();
-		Ok(Self { path, bytes })
+		Ok(Self {
+			path,
+			bytes,
+			debuglink: None,
+			debug: None,
+		})
 	}
 
 	pub fn path(&self) -> &Path { &self.path }
 
+	/// CRC32 of the bytes which are scanned, after extracting a kernel and patching alternatives
+	pub fn checksum(&self) -> u32 {
+		let mut crc = Crc::new();
+		crc.update(&self.bytes);
+		crc.sum()
+	}
+
+	/// Resolves symbols from a separate file, eg. the debug file of a stripped kernel, instead of the
+	/// one named by `.gnu_debuglink`
+	pub fn symbols_from(&mut self, path: impl AsRef<Path>) -> Result<()> {
+		self.debug = Some(Box::new(Binary::load(path)?));
+		Ok(())
+	}
+
+	/// The compressor of a bzImage whose `vmlinux` could not be extracted, eg. `zstd`, the file is
+	/// then scanned as it is
+	pub fn packed_kernel(&self) -> Option<&'static str> {
+		bzimage_payload(&self.bytes).map(compression)
+	}
+
+	/// The name in `.gnu_debuglink` when the binary is stripped and the debug file could not be found
+	pub fn missing_debuglink(&self) -> Option<&str> {
+		match self.debug {
+			Some(_) => None,
+			None => self.debuglink.as_deref(),
+		}
+	}
+
+	// Stripped binaries can name a separate debug file holding their symbols, look for it in the
+	// same places gdb does
+	fn follow_debuglink(&mut self) {
+		let elf = match Object::parse(&self.bytes) {
+			Ok(Object::Elf(e)) => e,
+			_ => return,
+		};
+		if !elf.syms.is_empty() {
+			return;
+		}
+		let debuglink = elf
+			.section_headers
+			.iter()
+			.find(|header| elf.shdr_strtab.get_at(header.sh_name) == Some(".gnu_debuglink"))
+			.and_then(|header| {
+				let start = header.sh_offset as usize;
+				let end = start.checked_add(header.sh_size as usize)?;
+				let data = self.bytes.get(start..end)?;
+				let len = data.iter().position(|b| *b == 0)?;
+				let name = String::from_utf8(data[..len].to_vec()).ok()?;
+				// The name is NUL padded to 4 bytes and followed by the debug file's CRC32
+				let crc = (len + 1).next_multiple_of(4);
+				let crc = data.get(crc..crc + 4)?.try_into().ok()?;
+				Some((name, u32::from_le_bytes(crc)))
+			});
+		let (debuglink, crc) = match debuglink {
+			Some(d) => d,
+			None => return,
+		};
+
+		let dir = self.path.parent().unwrap_or(Path::new("")).to_path_buf();
+		let mut candidates = vec![dir.join(&debuglink), dir.join(".debug").join(&debuglink)];
+		if let Ok(abs) = dir.canonicalize() {
+			let relative = abs.strip_prefix("/").unwrap_or(&abs);
+			candidates.push(Path::new("/usr/lib/debug").join(relative).join(&debuglink));
+		}
+		let build_id = elf
+			.iter_note_sections(&self.bytes, Some(".note.gnu.build-id"))
+			.into_iter()
+			.flatten()
+			.filter_map(|note| note.ok())
+			.find(|note| note.n_type == NT_GNU_BUILD_ID)
+			.map(|note| note.desc.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+		if let Some(build_id) = build_id.filter(|id| id.len() > 2) {
+			candidates.push(
+				Path::new("/usr/lib/debug/.build-id")
+					.join(&build_id[..2])
+					.join(format!("{}.debug", &build_id[2..])),
+			);
+		}
+		// A debug file from another build of the binary would resolve the wrong addresses
+		self.debug = candidates
+			.into_iter()
+			.filter(|candidate| candidate != &self.path)
+			.find_map(|candidate| {
+				let bytes = read(&candidate).ok()?;
+				let mut sum = Crc::new();
+				sum.update(&bytes);
+				(sum.sum() == crc).then_some(Binary {
+					path: candidate,
+					bytes,
+					debuglink: None,
+					debug: None,
+				})
+			})
+			.map(Box::new);
+		self.debuglink = Some(debuglink);
+	}
+
     pub fn get_sym_addr(&self, fnname: &str) -> Option<u64> {
-        let elf = match Object::parse(&self.bytes).expect("couldn't parse object???, is this a vmlinux?") {
-            Object::Elf(e) => e,
-            _ => {
-                panic!("wtf expected an elf, gimme a vmlinux");
-            }
+        self.get_all_sym_addrs(fnname).first().copied()
+    }
+
+    /// Every address `fnname` resolves to, eg. for local duplicates in a kernel, functions first
+    pub fn get_all_sym_addrs(&self, fnname: &str) -> Vec<u64> {
+        // Raw blobs and non-ELF objects have no symbols to resolve
+        let elf = match Object::parse(&self.bytes) {
+            Ok(Object::Elf(e)) => e,
+            _ => return Vec::new(),
         };
-        let matched: Vec<u64> = elf.syms.iter().filter(|s| {
+        // Prefer a function when a data symbol shares its name
+        let addresses = |mut matched: Vec<Sym>| {
+            matched.sort_by_key(|s| !s.is_function());
+            matched.iter().fold(Vec::new(), |mut addresses, s| {
+                if !addresses.contains(&s.st_value) {
+                    addresses.push(s.st_value);
+                }
+                addresses
+            })
+        };
+        let matched: Vec<Sym> = elf.syms.iter().filter(|s| {
                 elf.strtab.get_at(s.st_name).unwrap_or("") == fnname
             })
-            .map(|s| s.st_value)
             .collect();
+        if !matched.is_empty() {
+            return addresses(matched)
+        }
 
-        if matched.len() > 0 {
-            return Some(matched[0])
+        // Stripped but dynamically linked binaries still name their exports in `.dynsym`
+        let matched: Vec<Sym> = elf.dynsyms.iter().filter(|s| {
+                elf.dynstrtab.get_at(s.st_name).unwrap_or("") == fnname
+            })
+            .collect();
+        if !matched.is_empty() {
+            return addresses(matched)
         }
-        None
+        self.debug.as_ref().map_or_else(Vec::new, |debug| debug.get_all_sym_addrs(fnname))
     }
 
-	pub fn sections(&self, raw: Option<bool>) -> Result<Vec<Section>> {
+	/// Whether `name` is a `STT_GNU_IFUNC` symbol, its address is then that of the resolver which picks
+	/// the implementation at load time rather than of the implementation itself
+	pub fn is_ifunc(&self, name: &str) -> bool {
+		let elf = match Object::parse(&self.bytes) {
+			Ok(Object::Elf(e)) => e,
+			_ => return false,
+		};
+		let ifunc = elf
+			.syms
+			.iter()
+			.any(|s| s.st_type() == STT_GNU_IFUNC && elf.strtab.get_at(s.st_name) == Some(name))
+			|| elf
+				.dynsyms
+				.iter()
+				.any(|s| s.st_type() == STT_GNU_IFUNC && elf.dynstrtab.get_at(s.st_name) == Some(name));
+		ifunc || self.debug.as_ref().is_some_and(|debug| debug.is_ifunc(name))
+	}
+
+	/// Addresses and names of the function symbols, including those of a separate debug file
+	pub fn function_symbols(&self) -> Vec<(u64, String)> {
+		let elf = match Object::parse(&self.bytes) {
+			Ok(Object::Elf(e)) => e,
+			_ => return Vec::new(),
+		};
+		let mut symbols = elf
+			.syms
+			.iter()
+			.filter_map(|s| Some((s, elf.strtab.get_at(s.st_name)?)))
+			.chain(elf.dynsyms.iter().filter_map(|s| Some((s, elf.dynstrtab.get_at(s.st_name)?))))
+			.filter(|(s, name)| s.is_function() && s.st_value != 0 && !name.is_empty())
+			.map(|(s, name)| (s.st_value, name.to_string()))
+			.collect::<Vec<_>>();
+		if let Some(debug) = &self.debug {
+			symbols.extend(debug.function_symbols());
+		}
+		symbols
+	}
+
+	/// Every named symbol sorted by address, for finding which symbol an address falls in. Includes
+	/// those of a separate debug file
+	pub fn symbol_index(&self) -> SymbolIndex { SymbolIndex::new(self.symbols()) }
+
+	fn symbols(&self) -> Vec<(u64, u64, String)> {
+		let elf = match Object::parse(&self.bytes) {
+			Ok(Object::Elf(e)) => e,
+			_ => return Vec::new(),
+		};
+		let mut symbols = elf
+			.syms
+			.iter()
+			.filter_map(|s| Some((s, elf.strtab.get_at(s.st_name)?)))
+			.chain(elf.dynsyms.iter().filter_map(|s| Some((s, elf.dynstrtab.get_at(s.st_name)?))))
+			.filter(|(s, name)| {
+				!matches!(s.st_type(), STT_SECTION | STT_FILE) && s.st_value != 0 && !name.is_empty()
+			})
+			.map(|(s, name)| (s.st_value, s.st_size, name.to_string()))
+			.collect::<Vec<_>>();
+		if let Some(debug) = &self.debug {
+			symbols.extend(debug.symbols());
+		}
+		symbols
+	}
+
+	/// Maps the GOT slots filled in by the dynamic linker to the names of the symbols they resolve to
+	pub fn got_imports(&self) -> Vec<(u64, String)> {
+		let elf = match Object::parse(&self.bytes) {
+			Ok(Object::Elf(e)) => e,
+			_ => return Vec::new(),
+		};
+		elf.pltrelocs
+			.iter()
+			.chain(elf.dynrelas.iter())
+			.chain(elf.dynrels.iter())
+			.filter_map(|reloc| {
+				let sym = elf.dynsyms.get(reloc.r_sym)?;
+				let name = elf.dynstrtab.get_at(sym.st_name)?;
+				(!name.is_empty()).then(|| (reloc.r_offset, name.to_string()))
+			})
+			.collect()
+	}
+
+	pub fn soname(&self) -> Option<String> {
+		match Object::parse(&self.bytes) {
+			Ok(Object::Elf(e)) => e.soname.map(String::from),
+			_ => None,
+		}
+	}
+
+	// Malformed or packed binaries can declare sections extending past the end of the file, clamp
+	// these to the bytes which are present
+	fn file_range(&self, start_offset: usize, size: usize) -> (&[u8], bool) {
+		let start = start_offset.min(self.bytes.len());
+		let end = start_offset.saturating_add(size);
+		let truncated = end > self.bytes.len();
+		(&self.bytes[start..end.min(self.bytes.len())], truncated)
+	}
+
+	/// Applies the kernel's `.altinstructions` the way boot time patching would on a cpu with every
+	/// feature, copying each replacement over its site and padding it with nops. Returns how many
+	/// sites were patched, binaries without alternatives are left as they are
+	pub fn apply_alternatives(&mut self) -> usize {
+		let patches = match Object::parse(&self.bytes) {
+			Ok(Object::Elf(e)) => self.alternative_patches(&e),
+			_ => return 0,
+		};
+		for (offset, bytes) in &patches {
+			self.bytes[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+		}
+		patches.len()
+	}
+
+	// `struct alt_instr` is two self relative offsets, the feature and the original and replacement
+	// lengths, but its size depends on the kernel version so try each layout until one decodes
+	fn alternative_patches(&self, elf: &Elf) -> Vec<(usize, Vec<u8>)> {
+		let find = |name| {
+			elf.section_headers
+				.iter()
+				.find(|header| elf.shdr_strtab.get_at(header.sh_name) == Some(name))
+		};
+		let table = find(".altinstructions");
+		let replacements = find(".altinstr_replacement");
+		let (table, replacements) = match (table, replacements) {
+			(Some(t), Some(r)) => (t, r),
+			_ => return Vec::new(),
+		};
+		let (entries, truncated) =
+			self.file_range(table.sh_offset as usize, table.sh_size as usize);
+		if entries.is_empty() || truncated {
+			return Vec::new();
+		}
+		// File offset of `len` bytes at `vaddr` when they are in a section with file contents
+		let file_offset = |vaddr: u64, len: usize, executable: bool| {
+			let header = elf.section_headers.iter().find(|header| {
+				header.sh_type == SHT_PROGBITS
+					&& (!executable || header.sh_flags & SHF_EXECINSTR as u64 != 0)
+					&& header.sh_addr <= vaddr
+					&& vaddr.saturating_add(len as u64) <= header.sh_addr + header.sh_size
+			})?;
+			let offset = (header.sh_offset + vaddr - header.sh_addr) as usize;
+			(offset + len <= self.bytes.len()).then_some(offset)
+		};
+
+		for layout in ALT_INSTR_LAYOUTS {
+			if entries.len() % layout.size != 0 {
+				continue;
+			}
+			let mut patches = Vec::new();
+			let decoded = entries.chunks_exact(layout.size).enumerate().all(|(i, entry)| {
+				let base = table.sh_addr + (i * layout.size) as u64;
+				let instr_offset = i32::from_le_bytes(entry[0..4].try_into().unwrap());
+				let repl_offset = i32::from_le_bytes(entry[4..8].try_into().unwrap());
+				let instr = base.wrapping_add_signed(instr_offset as i64);
+				let repl = (base + 4).wrapping_add_signed(repl_offset as i64);
+				let instr_len = entry[layout.instrlen_at] as usize;
+				let repl_len = entry[layout.repllen_at] as usize;
+				let repl_end = replacements.sh_addr + replacements.sh_size;
+				let in_replacements =
+					repl >= replacements.sh_addr && repl.saturating_add(repl_len as u64) <= repl_end;
+				// The kernel never grows a site, and replacements all live in their own section
+				if instr_len == 0 || repl_len > instr_len || (repl_len > 0 && !in_replacements) {
+					return false;
+				}
+				let site = file_offset(instr, instr_len, true);
+				let source = file_offset(repl, repl_len, false);
+				let (site, source) = match (site, source) {
+					(Some(site), Some(source)) => (site, source),
+					_ => return false,
+				};
+				let flags = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+				// Replacements for a missing feature don't apply when every feature is present, and
+				// direct calls are only resolved at boot, so either leaves the original bytes
+				if flags & (layout.not_flag | layout.direct_call_flag) != 0 {
+					return true;
+				}
+				let bitness = if elf.is_64 { 64 } else { 32 };
+				let replacement = &self.bytes[source..source + repl_len];
+				let mut bytes = match relocate(replacement, repl, instr, bitness) {
+					Some(bytes) => bytes,
+					None => return true,
+				};
+				bytes.extend(nops(instr_len - repl_len));
+				patches.push((site, bytes));
+				true
+			});
+			if decoded {
+				return patches;
+			}
+		}
+		Vec::new()
+	}
+
+	// Thin Mach-O images mark code sections with instruction attributes rather than permissions
+	fn macho_sections(&self, m: &MachO) -> Result<Vec<Section<'_>>> {
+		let bitness = match m.header.cputype() {
+			CPU_TYPE_X86_64 => Bitness::Bits64,
+			CPU_TYPE_X86 => Bitness::Bits32,
+			_ => return Err(Error::Unsupported),
+		};
+		let mut sections = Vec::new();
+		for segment in m.segments.iter() {
+			for (section, _) in segment.sections()? {
+				let name = format!(
+					"{},{}",
+					section.segname().unwrap_or(""),
+					section.name().unwrap_or("")
+				);
+				let executable = section.flags & (S_ATTR_PURE_INSTRUCTIONS | S_ATTR_SOME_INSTRUCTIONS) != 0
+					|| name == "__TEXT,__text";
+				if !executable {
+					continue;
+				}
+				let start_offset = section.offset as usize;
+				let (bytes, truncated) = self.file_range(start_offset, section.size as usize);
+				sections.push(Section {
+					name,
+					file_offset: start_offset,
+					section_vaddr: section.addr as usize,
+					program_base: 0,
+					bytes,
+					bitness,
+					truncated,
+				});
+			}
+		}
+		Ok(sections)
+	}
+
+	/// `bitness` overrides the mode raw blobs are decoded in, parsed formats take it from their
+	/// headers. `names` picks the ELF sections to scan instead of `.text`, `all-exec` standing for
+	/// every executable section
+	pub fn sections(
+		&self,
+		raw: Option<bool>,
+		bitness: Option<Bitness>,
+		names: &[String],
+	) -> Result<Vec<Section<'_>>> {
 		match raw {
 			Some(true) => Ok(vec![Section {
+				name: String::from("raw"),
 				file_offset: 0,
 				section_vaddr: 0,
 				program_base: 0,
 				bytes: &self.bytes,
-				bitness: Bitness::Bits64,
+				bitness: bitness.unwrap_or(Bitness::Bits64),
+				truncated: false,
 			}]),
 			Some(false) => match Object::parse(&self.bytes)? {
 				Object::Elf(e) => {
@@ -65,16 +499,19 @@ impl Binary {
 					let sections = e
 						.program_headers
 						.iter()
-						.filter(|header| header.p_flags & PF_X != 0)
-						.map(|header| {
+						.enumerate()
+						.filter(|(_, header)| header.p_flags & PF_X != 0)
+						.map(|(index, header)| {
 							let start_offset = header.p_offset as usize;
-							let end_offset = start_offset + header.p_filesz as usize;
+							let (bytes, truncated) = self.file_range(start_offset, header.p_filesz as usize);
 							Section {
+								name: format!("segment{}", index),
 								file_offset: start_offset,
 								section_vaddr: header.p_vaddr as usize,
 								program_base: 0,
-								bytes: &self.bytes[start_offset..end_offset],
+								bytes,
 								bitness,
+								truncated,
 							}
 						})
 						.collect::<Vec<_>>();
@@ -93,18 +530,22 @@ impl Binary {
 						.filter(|section| (section.characteristics & IMAGE_SCN_MEM_EXECUTE) != 0)
 						.map(|section| {
 							let start_offset = section.pointer_to_raw_data as usize;
-							let end_offset = start_offset + section.size_of_raw_data as usize;
+							let (bytes, truncated) =
+								self.file_range(start_offset, section.size_of_raw_data as usize);
 							Section {
+								name: section.name().unwrap_or("").to_string(),
 								file_offset: start_offset,
 								section_vaddr: section.virtual_address as usize,
 								program_base: p.image_base,
-								bytes: &self.bytes[start_offset..end_offset],
+								bytes,
 								bitness,
+								truncated,
 							}
 						})
 						.collect::<Vec<_>>();
 					Ok(sections)
 				}
+				Object::Mach(Mach::Binary(m)) => self.macho_sections(&m),
 				Object::Unknown(_) => Err(Error::ParseErr),
 				_ => Err(Error::Unsupported),
 			},
@@ -121,19 +562,25 @@ impl Binary {
 						.section_headers
 						.iter()
 						.filter(|header| {
-							e.shdr_strtab
-							.get_at(header.sh_name)
-							.unwrap_or("") == ".text"
+							let name = e.shdr_strtab.get_at(header.sh_name).unwrap_or("");
+							if names.is_empty() {
+								return name == ".text";
+							}
+							names.iter().any(|n| n == name)
+								|| (names.iter().any(|n| n == "all-exec")
+									&& header.sh_flags & SHF_EXECINSTR as u64 != 0)
 						})
 						.map(|header| {
 							let start_offset = header.sh_offset as usize;
-							let end_offset = start_offset + header.sh_size as usize;
+							let (bytes, truncated) = self.file_range(start_offset, header.sh_size as usize);
 							Section {
+								name: e.shdr_strtab.get_at(header.sh_name).unwrap_or("").to_string(),
 								file_offset: start_offset,
-								section_vaddr: header.sh_addr as usize,
+								section_vaddr: mapped_vaddr(header, &e.program_headers) as usize,
 								program_base: 0,
-								bytes: &self.bytes[start_offset..end_offset],
+								bytes,
 								bitness,
+								truncated,
 							}
 						})
 						.collect::<Vec<_>>();
@@ -152,39 +599,212 @@ impl Binary {
 						.filter(|section| (section.characteristics & IMAGE_SCN_MEM_EXECUTE) != 0)
 						.map(|section| {
 							let start_offset = section.pointer_to_raw_data as usize;
-							let end_offset = start_offset + section.size_of_raw_data as usize;
+							let (bytes, truncated) =
+								self.file_range(start_offset, section.size_of_raw_data as usize);
 							Section {
+								name: section.name().unwrap_or("").to_string(),
 								file_offset: start_offset,
 								section_vaddr: section.virtual_address as usize,
 								program_base: p.image_base,
-								bytes: &self.bytes[start_offset..end_offset],
+								bytes,
 								bitness,
+								truncated,
 							}
 						})
 						.collect::<Vec<_>>();
 					Ok(sections)
 				}
+				Object::Mach(Mach::Binary(m)) => self.macho_sections(&m),
+				// Universal binaries hold one image per architecture, scanning them as raw would mix them
+				Object::Mach(Mach::Fat(_)) => Err(Error::Unsupported),
 				_ => Ok(vec![Section {
+					name: String::from("raw"),
 					file_offset: 0,
 					section_vaddr: 0,
 					program_base: 0,
 					bytes: &self.bytes,
-					bitness: Bitness::Bits32,
+					bitness: bitness.unwrap_or(Bitness::Bits32),
+					truncated: false,
 				}]),
 			},
 		}
 	}
 }
 
+/// Where the loader maps a section, from the `PT_LOAD` segment containing its file offset. This
+/// can disagree with `sh_addr` in prelinked or unusually laid out binaries, sections outside any
+/// segment keep `sh_addr`
+fn mapped_vaddr(header: &SectionHeader, segments: &[ProgramHeader]) -> u64 {
+	segments
+		.iter()
+		.find(|segment| {
+			segment.p_type == PT_LOAD
+				&& segment.p_offset <= header.sh_offset
+				&& header.sh_offset < segment.p_offset + segment.p_filesz
+		})
+		.map_or(header.sh_addr, |segment| segment.p_vaddr + (header.sh_offset - segment.p_offset))
+}
+
+/// The compressed payload of a bzImage, `None` when `bytes` isn't a bzImage
+fn bzimage_payload(bytes: &[u8]) -> Option<&[u8]> {
+	let u32_at = |offset: usize| {
+		let b = bytes.get(offset..offset + 4)?;
+		Some(u32::from_le_bytes(b.try_into().unwrap()) as usize)
+	};
+	// The setup header's magic, the payload fields are there from boot protocol 2.08
+	if bytes.get(0x202..0x206) != Some(b"HdrS") {
+		return None;
+	}
+	let version = u16::from_le_bytes(bytes.get(0x206..0x208)?.try_into().unwrap());
+	if version < 0x208 {
+		return None;
+	}
+	let setup_sects = match *bytes.get(0x1f1)? {
+		0 => 4,
+		n => n as usize,
+	};
+	let start = (setup_sects + 1) * 512 + u32_at(0x248)?;
+	bytes.get(start..start.checked_add(u32_at(0x24c)?)?)
+}
+
+/// Names the compressor of a bzImage payload from its magic
+fn compression(payload: &[u8]) -> &'static str {
+	const MAGICS: [(&[u8], &str); 7] = [
+		(&[0x1f, 0x8b], "gzip"),
+		(&[0xfd, b'7', b'z', b'X', b'Z'], "xz"),
+		(&[0x28, 0xb5, 0x2f, 0xfd], "zstd"),
+		(&[0x02, 0x21, 0x4c, 0x18], "lz4"),
+		(b"BZh", "bzip2"),
+		(&[0x5d, 0x00, 0x00], "lzma"),
+		(&[0x89, b'L', b'Z', b'O'], "lzo"),
+	];
+	MAGICS
+		.iter()
+		.find(|(magic, _)| payload.starts_with(magic))
+		.map_or("unknown", |(_, name)| name)
+}
+
+/// Decompresses the `vmlinux` ELF a bzImage wraps, `None` when `bytes` isn't a bzImage or its
+/// payload can't be unpacked into an ELF, in which case the file is scanned as it is
+fn extract_vmlinux(bytes: &[u8]) -> Option<Vec<u8>> {
+	let payload = bzimage_payload(bytes)?;
+	let mut vmlinux = Vec::new();
+	let decoded = match compression(payload) {
+		"gzip" => GzDecoder::new(payload).read_to_end(&mut vmlinux),
+		// The build appends the decompressed size, the xz decoder would take it for another stream
+		"xz" => {
+			let stream = &payload[..payload.len().saturating_sub(4)];
+			XzDecoder::new(stream).read_to_end(&mut vmlinux)
+		}
+		_ => return None,
+	};
+	(decoded.is_ok() && vmlinux.starts_with(b"\x7fELF")).then_some(vmlinux)
+}
+
+struct AltInstrLayout {
+	size: usize,
+	/// Offsets of the `instrlen` and `replacementlen` bytes
+	instrlen_at: usize,
+	repllen_at: usize,
+	/// Bit of the little endian word following the offsets marking a replacement for cpus without
+	/// the feature
+	not_flag: u32,
+	/// Bit marking a `call *rel(%rip)` the kernel turns into a direct call to the pointer's target
+	direct_call_flag: u32,
+}
+
+// Since 6.3 the feature and flags are 32 bits, from 5.13 the feature is 16 bits with the top bit
+// inverting it, and 4.x kernels have a `padlen` byte after the lengths and no inverted features
+const ALT_INSTR_LAYOUTS: [AltInstrLayout; 3] = [
+	AltInstrLayout {
+		size: 14,
+		instrlen_at: 12,
+		repllen_at: 13,
+		not_flag: 1 << 16,
+		direct_call_flag: 1 << 17,
+	},
+	AltInstrLayout {
+		size: 12,
+		instrlen_at: 10,
+		repllen_at: 11,
+		not_flag: 1 << 15,
+		direct_call_flag: 0,
+	},
+	AltInstrLayout {
+		size: 13,
+		instrlen_at: 10,
+		repllen_at: 11,
+		not_flag: 0,
+		direct_call_flag: 0,
+	},
+];
+
+/// Moves the `bytes` of a replacement at `from` to run at `to`, re-encoding relative branches and
+/// RIP-relative operands to reach the same targets like the kernel's `apply_relocation`. `None`
+/// when an instruction doesn't decode or doesn't fit in its original length once moved
+fn relocate(bytes: &[u8], from: u64, to: u64, bitness: u32) -> Option<Vec<u8>> {
+	let mut relocated = Vec::with_capacity(bytes.len());
+	for instr in Decoder::with_ip(bitness, bytes, from, DecoderOptions::NONE) {
+		if instr.is_invalid() {
+			return None;
+		}
+		let start = (instr.ip() - from) as usize;
+		let original = &bytes[start..start + instr.len()];
+		let relative = instr.is_ip_rel_memory_operand()
+			|| (0..instr.op_count()).any(|i| {
+				matches!(
+					instr.op_kind(i),
+					OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64
+				)
+			});
+		if !relative {
+			relocated.extend_from_slice(original);
+			continue;
+		}
+		let mut encoder = Encoder::new(bitness);
+		let len = encoder.encode(&instr, to + start as u64).ok()?;
+		if len != instr.len() {
+			return None;
+		}
+		relocated.extend(encoder.take_buffer());
+	}
+	Some(relocated)
+}
+
+/// The kernel's long nops, `x86_nops`, padding `len` bytes
+fn nops(mut len: usize) -> Vec<u8> {
+	const NOPS: [&[u8]; 8] = [
+		&[0x90],
+		&[0x66, 0x90],
+		&[0x0f, 0x1f, 0x00],
+		&[0x0f, 0x1f, 0x40, 0x00],
+		&[0x0f, 0x1f, 0x44, 0x00, 0x00],
+		&[0x66, 0x0f, 0x1f, 0x44, 0x00, 0x00],
+		&[0x0f, 0x1f, 0x80, 0x00, 0x00, 0x00, 0x00],
+		&[0x0f, 0x1f, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00],
+	];
+	let mut bytes = Vec::with_capacity(len);
+	while len > 0 {
+		let nop = NOPS[len.min(NOPS.len()) - 1];
+		bytes.extend_from_slice(nop);
+		len -= nop.len();
+	}
+	bytes
+}
+
 pub struct Section<'b> {
+	name: String,
 	file_offset: usize,
 	section_vaddr: usize,
 	program_base: usize,
 	bitness: Bitness,
 	bytes: &'b [u8],
+	truncated: bool,
 }
 
 impl Section<'_> {
+	pub fn name(&self) -> &str { &self.name }
+
 	pub fn file_offset(&self) -> usize { self.file_offset }
 
 	pub fn section_vaddr(&self) -> usize { self.section_vaddr }
@@ -194,4 +814,358 @@ impl Section<'_> {
 	pub fn bitness(&self) -> Bitness { self.bitness }
 
 	pub fn bytes(&self) -> &[u8] { self.bytes }
+
+	/// Whether the section header claimed more bytes than the file holds
+	pub fn truncated(&self) -> bool { self.truncated }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use flate2::{write::GzEncoder, Compression};
+	use goblin::{
+		elf::{
+			dynamic::{DT_HASH, DT_NULL, DT_STRSZ, DT_STRTAB, DT_SYMENT, DT_SYMTAB},
+			section_header::{SHF_ALLOC, SHT_DYNAMIC, SHT_DYNSYM, SHT_HASH, SHT_STRTAB, SHT_SYMTAB},
+			sym::{STT_FUNC, STT_OBJECT},
+		},
+		elf64::program_header::{PT_DYNAMIC, PT_NOTE},
+	};
+	use std::io::Write;
+
+	const VMLINUX: &[u8] = b"\x7fELF\x02\x01\x01vmlinux";
+
+	// A setup header for boot protocol 2.15 with one setup sector, followed by `payload`
+	fn bzimage(payload: &[u8]) -> Vec<u8> {
+		let mut bytes = vec![0; 2 * 512];
+		bytes[0x1f1] = 1;
+		bytes[0x202..0x206].copy_from_slice(b"HdrS");
+		bytes[0x206..0x208].copy_from_slice(&0x20fu16.to_le_bytes());
+		bytes[0x248..0x24c].copy_from_slice(&0u32.to_le_bytes());
+		bytes[0x24c..0x250].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+		bytes.extend_from_slice(payload);
+		bytes
+	}
+
+	/// Address and file offset of the nth section of an `elf` fixture
+	fn section_addr(n: usize) -> u64 { (n as u64 + 1) * 0x1000 }
+
+	/// A little endian 64-bit ELF of `sections`, each a name, type, flags and contents, placed at
+	/// `section_addr` and loaded at their file offsets. `.symtab` and `.dynsym` link to `.strtab`
+	/// and `.dynstr`, and `.dynamic` is also the `PT_DYNAMIC` segment
+	fn elf(sections: &[(&str, u32, u64, Vec<u8>)]) -> Vec<u8> {
+		let index = |name| sections.iter().position(|(n, ..)| *n == name).map_or(0, |i| i + 1);
+		// `Elf64_Shdr` with the name, type, flags, address and offset, size, link and entry size
+		let section_header = |fields: (usize, u32, u64, u64, usize, usize, u64)| {
+			let (name, sh_type, flags, addr, size, link, entsize) = fields;
+			let mut header = Vec::with_capacity(64);
+			header.extend((name as u32).to_le_bytes());
+			header.extend(sh_type.to_le_bytes());
+			for field in [flags, addr, addr, size as u64] {
+				header.extend(field.to_le_bytes());
+			}
+			header.extend((link as u32).to_le_bytes());
+			header.extend(0u32.to_le_bytes());
+			header.extend(8u64.to_le_bytes());
+			header.extend(entsize.to_le_bytes());
+			header
+		};
+
+		let mut bytes = vec![0; section_addr(sections.len()) as usize];
+		let mut shstrtab = vec![0];
+		let mut headers = vec![0; 64];
+		for (n, (name, sh_type, flags, data)) in sections.iter().enumerate() {
+			let addr = section_addr(n);
+			assert!(data.len() <= 0x1000);
+			bytes[addr as usize..addr as usize + data.len()].copy_from_slice(data);
+			let (link, entsize) = match *name {
+				".symtab" => (index(".strtab"), 24),
+				".dynsym" => (index(".dynstr"), 24),
+				".dynamic" => (index(".dynstr"), 16),
+				_ => (0, 0),
+			};
+			let fields = (shstrtab.len(), *sh_type, *flags, addr, data.len(), link, entsize);
+			headers.extend(section_header(fields));
+			shstrtab.extend(name.as_bytes());
+			shstrtab.push(0);
+		}
+		// `.shstrtab` isn't loaded, its address is its file offset like the other sections
+		let name = shstrtab.len();
+		shstrtab.extend(b".shstrtab\0");
+		let fields = (name, SHT_STRTAB, 0, bytes.len() as u64, shstrtab.len(), 0, 0);
+		headers.extend(section_header(fields));
+		bytes.extend(&shstrtab);
+		let shoff = bytes.len().next_multiple_of(8);
+		bytes.resize(shoff, 0);
+		bytes.extend(&headers);
+
+		let mut segments = vec![(PT_LOAD, 0, bytes.len() as u64)];
+		if let Some(n) = sections.iter().position(|(name, ..)| *name == ".dynamic") {
+			segments.push((PT_DYNAMIC, section_addr(n), sections[n].3.len() as u64));
+		}
+		let mut header = b"\x7fELF\x02\x01\x01".to_vec();
+		header.resize(16, 0);
+		header.extend(2u16.to_le_bytes());
+		header.extend(0x3eu16.to_le_bytes());
+		header.extend(1u32.to_le_bytes());
+		for field in [0, 64, shoff as u64] {
+			header.extend(field.to_le_bytes());
+		}
+		header.extend(0u32.to_le_bytes());
+		for field in [64, 56, segments.len(), 64, sections.len() + 2, sections.len() + 1] {
+			header.extend((field as u16).to_le_bytes());
+		}
+		for (p_type, offset, size) in segments {
+			header.extend(p_type.to_le_bytes());
+			header.extend(5u32.to_le_bytes());
+			for field in [offset, offset, offset, size, size, 8] {
+				header.extend(field.to_le_bytes());
+			}
+		}
+		bytes[..header.len()].copy_from_slice(&header);
+		bytes
+	}
+
+	#[test]
+	fn looks_up_the_preceding_symbol() {
+		let index = SymbolIndex::new(vec![
+			(0x2000, 0x80, String::from("memcpy")),
+			(0x1000, 0, String::from("label")),
+			(0x1000, 0x40, String::from("main")),
+			(0x1040, 0, String::from("main_end")),
+		]);
+		assert_eq!(index.lookup(0xfff), None);
+		// The sized symbol wins over a label at the same address
+		assert_eq!(index.lookup(0x1000), Some(("main", 0)));
+		assert_eq!(index.lookup(0x1010), Some(("main", 0x10)));
+		assert_eq!(index.lookup(0x1fff), Some(("main_end", 0xfbf)));
+		assert_eq!(index.lookup(0x2010), Some(("memcpy", 0x10)));
+		assert_eq!(index.lookup(0x207f), Some(("memcpy", 0x7f)));
+		// Past the end of a sized symbol
+		assert_eq!(index.lookup(0x2080), None);
+	}
+
+	/// An `Elf64_Sym` named by the string table offset `name`, defined in section 1
+	fn sym(name: usize, st_type: u8, value: u64) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(24);
+		bytes.extend((name as u32).to_le_bytes());
+		bytes.extend([1 << 4 | st_type, 0]);
+		bytes.extend(1u16.to_le_bytes());
+		bytes.extend(value.to_le_bytes());
+		bytes.extend(0u64.to_le_bytes());
+		bytes
+	}
+
+	/// A binary whose `.symtab` has a function and a data symbol both named `counter`, and whose
+	/// `.dynsym` alone has `exported`
+	fn symbols() -> Binary {
+		let symtab = [vec![0; 24], sym(1, STT_OBJECT, 0x5000), sym(1, STT_FUNC, 0x1010)].concat();
+		let dynsym = [vec![0; 24], sym(1, STT_FUNC, 0x1020)].concat();
+		let dynstr = b"\0exported\0".to_vec();
+		// A SysV hash table of one bucket, it only gives the number of dynamic symbols
+		let hash = [1u32, 2, 0, 0, 0].iter().flat_map(|w| w.to_le_bytes()).collect();
+		let dynamic = [
+			(DT_HASH, section_addr(5)),
+			(DT_STRTAB, section_addr(4)),
+			(DT_SYMTAB, section_addr(3)),
+			(DT_STRSZ, dynstr.len() as u64),
+			(DT_SYMENT, 24),
+			(DT_NULL, 0),
+		];
+		let dynamic = dynamic.iter().flat_map(|(tag, value)| [*tag, *value]);
+		let dynamic = dynamic.flat_map(u64::to_le_bytes).collect();
+		let bytes = elf(&[
+			(".text", SHT_PROGBITS, (SHF_ALLOC | SHF_EXECINSTR) as u64, vec![0xc3; 0x30]),
+			(".symtab", SHT_SYMTAB, 0, symtab),
+			(".strtab", SHT_STRTAB, 0, b"\0counter\0".to_vec()),
+			(".dynsym", SHT_DYNSYM, SHF_ALLOC as u64, dynsym),
+			(".dynstr", SHT_STRTAB, SHF_ALLOC as u64, dynstr),
+			(".hash", SHT_HASH, SHF_ALLOC as u64, hash),
+			(".dynamic", SHT_DYNAMIC, SHF_ALLOC as u64, dynamic),
+		]);
+		Binary::from_reader(bytes.as_slice()).unwrap()
+	}
+
+	#[test]
+	fn functions_come_before_data_of_the_same_name() {
+		let binary = symbols();
+		assert_eq!(binary.get_all_sym_addrs("counter"), [0x1010, 0x5000]);
+		assert_eq!(binary.get_sym_addr("counter"), Some(0x1010));
+	}
+
+	#[test]
+	fn falls_back_to_dynamic_symbols() {
+		let binary = symbols();
+		assert_eq!(binary.get_sym_addr("exported"), Some(0x1020));
+		assert_eq!(binary.get_sym_addr("missing"), None);
+	}
+
+	#[test]
+	fn section_vaddr_comes_from_its_segment() {
+		let segments = [ProgramHeader {
+			p_type: PT_LOAD,
+			p_offset: 0x1000,
+			p_vaddr: 0x401000,
+			p_filesz: 0x2000,
+			..Default::default()
+		}];
+		// `sh_addr` disagrees with the segment, eg. in a prelinked binary
+		let mut text = SectionHeader {
+			sh_offset: 0x1040,
+			sh_addr: 0x1040,
+			sh_size: 0x100,
+			..Default::default()
+		};
+		assert_eq!(mapped_vaddr(&text, &segments), 0x401040);
+		// Outside of every segment
+		text.sh_offset = 0x3000;
+		assert_eq!(mapped_vaddr(&text, &segments), 0x1040);
+		// Only loaded segments map sections
+		let note = [ProgramHeader { p_type: PT_NOTE, ..segments[0].clone() }];
+		text.sh_offset = 0x1040;
+		assert_eq!(mapped_vaddr(&text, &note), 0x1040);
+	}
+
+	/// A `struct alt_instr` at `entry` as the kernel of `layout` lays it out, replacing `len` bytes
+	/// at `site` with `repl_len` bytes at `repl`
+	fn alt_instr(
+		layout: &AltInstrLayout,
+		entry: u64,
+		(site, len): (u64, u8),
+		(repl, repl_len): (u64, u8),
+		flags: u32,
+	) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		bytes.extend((site.wrapping_sub(entry) as i32).to_le_bytes());
+		bytes.extend((repl.wrapping_sub(entry + 4) as i32).to_le_bytes());
+		match layout.size {
+			// u32 ft_flags; u8 instrlen; u8 replacementlen
+			14 => bytes.extend(flags.to_le_bytes()),
+			// u16 cpuid; u8 instrlen; u8 replacementlen
+			12 => bytes.extend((flags as u16).to_le_bytes()),
+			// u16 cpuid; u8 instrlen; u8 replacementlen; u8 padlen
+			_ => bytes.extend(0u16.to_le_bytes()),
+		}
+		bytes.extend([len, repl_len]);
+		if layout.size == 13 {
+			bytes.push(len.saturating_sub(repl_len));
+		}
+		assert_eq!(bytes.len(), layout.size);
+		bytes
+	}
+
+	/// A kernel whose `.text` of int3s has alternatives for each of `patches`, a site, its length,
+	/// the replacement and the flags
+	fn alternatives(layout: &AltInstrLayout, patches: &[(u64, u8, Vec<u8>, u32)]) -> Binary {
+		let (replacements, table) = (section_addr(1), section_addr(2));
+		let mut repl_bytes = Vec::new();
+		let mut entries = Vec::new();
+		for (site, len, replacement, flags) in patches {
+			let entry = table + entries.len() as u64;
+			let repl = replacements + repl_bytes.len() as u64;
+			let repl_len = replacement.len() as u8;
+			entries.extend(alt_instr(layout, entry, (*site, *len), (repl, repl_len), *flags));
+			repl_bytes.extend(replacement);
+		}
+		let executable = (SHF_ALLOC | SHF_EXECINSTR) as u64;
+		let bytes = elf(&[
+			(".text", SHT_PROGBITS, executable, vec![0xcc; 0x100]),
+			(".altinstr_replacement", SHT_PROGBITS, executable, repl_bytes),
+			(".altinstructions", SHT_PROGBITS, SHF_ALLOC as u64, entries),
+		]);
+		Binary::from_reader(bytes.as_slice()).unwrap()
+	}
+
+	/// `instruction` with its rel32 at `at` pointing to `target` when it is placed at `address`
+	fn rel32(instruction: &[u8], at: usize, address: u64, target: u64) -> Vec<u8> {
+		let mut bytes = instruction.to_vec();
+		let next = address + instruction.len() as u64;
+		bytes[at..at + 4].copy_from_slice(&(target.wrapping_sub(next) as i32).to_le_bytes());
+		bytes
+	}
+
+	#[test]
+	fn applies_alternatives_in_each_layout() {
+		let repl = section_addr(1);
+		for layout in &ALT_INSTR_LAYOUTS {
+			// xor rax, rax padded with a 3 byte nop
+			let mut patches = vec![(0x1000, 6, vec![0x48, 0x31, 0xc0], 0)];
+			let mut expected = vec![(0x1000, vec![0x48, 0x31, 0xc0, 0x0f, 0x1f, 0x00])];
+			// call 0x1800 and mov rax, [rip+...] of 0x1900, moved from the replacement section
+			let call = [0xe8, 0, 0, 0, 0];
+			let load = [0x48, 0x8b, 0x05, 0, 0, 0, 0];
+			patches.push((0x1020, 5, rel32(&call, 1, repl + 3, 0x1800), 0));
+			expected.push((0x1020, rel32(&call, 1, 0x1020, 0x1800)));
+			patches.push((0x1030, 7, rel32(&load, 3, repl + 8, 0x1900), 0));
+			expected.push((0x1030, rel32(&load, 3, 0x1030, 0x1900)));
+			// jmp +0 can't reach its target from the site so the original bytes are kept
+			patches.push((0x1040, 2, vec![0xeb, 0x00], 0));
+			expected.push((0x1040, vec![0xcc; 2]));
+			// A replacement for cpus without the feature doesn't apply
+			if layout.not_flag != 0 {
+				patches.push((0x1050, 3, vec![0x31, 0xc0, 0x90], layout.not_flag));
+				expected.push((0x1050, vec![0xcc; 3]));
+			}
+			// call [rip+...] is only turned into a direct call at boot
+			if layout.direct_call_flag != 0 {
+				let call = vec![0xff, 0x15, 0, 0, 0, 0];
+				patches.push((0x1060, 6, call, layout.direct_call_flag));
+				expected.push((0x1060, vec![0xcc; 6]));
+			}
+
+			let mut binary = alternatives(layout, &patches);
+			let name = format!("{} byte layout", layout.size);
+			assert_eq!(binary.apply_alternatives(), 3, "{}", name);
+			for (site, bytes) in expected {
+				let site = site as usize;
+				assert_eq!(binary.bytes[site..site + bytes.len()], bytes, "{}", name);
+			}
+		}
+	}
+
+	#[test]
+	fn undecodable_alternatives_leave_the_text() {
+		for layout in &ALT_INSTR_LAYOUTS {
+			// A replacement longer than its site
+			let mut binary = alternatives(layout, &[(0x1000, 2, vec![0x48, 0x31, 0xc0], 0)]);
+			let original = binary.bytes.clone();
+			assert_eq!(binary.apply_alternatives(), 0);
+			assert_eq!(binary.bytes, original);
+		}
+	}
+
+	#[test]
+	fn extracts_gzip_payload() {
+		let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+		encoder.write_all(VMLINUX).unwrap();
+		let image = bzimage(&encoder.finish().unwrap());
+		assert_eq!(extract_vmlinux(&image).as_deref(), Some(VMLINUX));
+	}
+
+	#[test]
+	fn extracts_xz_payload() {
+		let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 6);
+		encoder.write_all(VMLINUX).unwrap();
+		let mut payload = encoder.finish().unwrap();
+		payload.extend_from_slice(&(VMLINUX.len() as u32).to_le_bytes());
+		assert_eq!(extract_vmlinux(&bzimage(&payload)).as_deref(), Some(VMLINUX));
+	}
+
+	#[test]
+	fn unsupported_payload_is_scanned_as_is() {
+		let image = bzimage(&[0x28, 0xb5, 0x2f, 0xfd, 0, 0, 0, 0]);
+		assert_eq!(extract_vmlinux(&image), None);
+		let binary = Binary::from_reader(image.as_slice()).unwrap();
+		assert_eq!(binary.packed_kernel(), Some("zstd"));
+		assert_eq!(binary.bytes, image);
+	}
+
+	#[test]
+	fn truncated_header_is_not_a_bzimage() {
+		let image = bzimage(&[]);
+		for len in [0x206, 0x207, 0x248] {
+			assert_eq!(bzimage_payload(&image[..len]), None);
+			assert_eq!(extract_vmlinux(&image[..len]), None);
+		}
+	}
 }
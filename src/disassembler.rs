@@ -1,11 +1,14 @@
 use crate::{
 	binary::{Bitness, Section},
-	gadgets::GadgetIterator,
-	rules::is_gadget_tail,
+	gadgets::{Gadget, GadgetIterator},
+	rules::{is_gadget_tail, is_landing_pad},
 };
-use iced_x86::{Decoder, DecoderOptions, Instruction};
+use iced_x86::{Code, Decoder, DecoderOptions, FlowControl, Instruction, Mnemonic};
+use rustc_hash::FxHashMap;
 
 const MAX_INSTRUCTION_LENGTH: usize = 15;
+const MAX_BASIC_BLOCK_SCAN: usize = 4096;
+const MIN_RETURN_THUNK_JUMPS: usize = 16;
 
 pub struct Disassembler<'b> {
 	decoder: Decoder<'b>,
@@ -15,6 +18,7 @@ impl<'b> Disassembler<'b> {
 	pub fn new(bitness: Bitness, bytes: &'b [u8]) -> Self {
 		let decoder = {
 			let bitness = match bitness {
+				Bitness::Bits16 => 16,
 				Bitness::Bits32 => 32,
 				Bitness::Bits64 => 64,
 			};
@@ -31,6 +35,108 @@ impl<'b> Disassembler<'b> {
 	}
 }
 
+/// Decodes a gadget of `count` instructions starting at `address`, used to rebuild gadgets from
+/// their textual form
+pub fn decode_gadget(
+	section: &Section,
+	address: usize,
+	count: usize,
+	uniq: bool,
+) -> Option<(Gadget, usize)> {
+	let start = section.program_base() + section.section_vaddr();
+	let bytes = section.bytes();
+	let mut offset = address.checked_sub(start)?;
+	let mut disassembler = Disassembler::new(section.bitness(), bytes);
+	let mut instructions = Vec::with_capacity(count);
+	for _ in 0..count {
+		if offset >= bytes.len() {
+			return None;
+		}
+		let mut instruction = Instruction::default();
+		disassembler.decode_at_offset((start + offset) as u64, offset, &mut instruction);
+		if instruction.is_invalid() {
+			return None;
+		}
+		offset += instruction.len();
+		instructions.push(instruction);
+	}
+	let unique_id = if uniq { 0 } else { address };
+	Some((Gadget::new(instructions, unique_id), address))
+}
+
+/// Finds the start of the basic block containing `address` by scanning backward for the nearest
+/// control-flow instruction which falls through into it, `None` if there is none within range
+pub fn basic_block_start(section: &Section, address: usize) -> Option<usize> {
+	let start = section.program_base() + section.section_vaddr();
+	let bytes = section.bytes();
+	let offset = address.checked_sub(start)?;
+	if offset > bytes.len() {
+		return None;
+	}
+	let mut disassembler = Disassembler::new(section.bitness(), bytes);
+	let mut instruction = Instruction::default();
+	let lowest = offset.saturating_sub(MAX_BASIC_BLOCK_SCAN);
+	for branch in (lowest..offset).rev() {
+		disassembler.decode_at_offset((start + branch) as u64, branch, &mut instruction);
+		if instruction.is_invalid() || instruction.flow_control() == FlowControl::Next {
+			continue;
+		}
+		// The block begins after the branch, but only if linear decoding from there lands on the
+		// gadget without passing through another control-flow instruction
+		let block = branch + instruction.len();
+		let mut current = block;
+		while current < offset {
+			disassembler.decode_at_offset((start + current) as u64, current, &mut instruction);
+			if instruction.is_invalid() || instruction.flow_control() != FlowControl::Next {
+				break;
+			}
+			current += instruction.len();
+		}
+		if current == offset {
+			return Some(start + block);
+		}
+	}
+	None
+}
+
+/// Looks for the return thunk of a stripped kernel: with return thunks enabled functions end in
+/// `jmp __x86_return_thunk` instead of `ret`, so the thunk is the most common direct jump target
+/// which is itself a `ret` or `jmp` padded with `int3`
+pub fn detect_return_thunk(sections: &[Section]) -> Option<u64> {
+	let mut targets = FxHashMap::default();
+	for section in sections {
+		let start = section.program_base() + section.section_vaddr();
+		let mut decoder = Disassembler::new(section.bitness(), section.bytes()).decoder;
+		decoder.set_ip(start as u64);
+		for instruction in &mut decoder {
+			if matches!(instruction.code(), Code::Jmp_rel32_64 | Code::Jmp_rel32_32) {
+				*targets.entry(instruction.near_branch_target()).or_insert(0) += 1;
+			}
+		}
+	}
+	let mut targets = targets
+		.into_iter()
+		.filter(|(_, count)| *count >= MIN_RETURN_THUNK_JUMPS)
+		.collect::<Vec<_>>();
+	targets.sort_unstable_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+	targets
+		.into_iter()
+		.map(|(target, _)| target)
+		.find(|target| is_return_thunk(sections, *target as usize))
+}
+
+fn is_return_thunk(sections: &[Section], address: usize) -> bool {
+	sections.iter().any(|section| {
+		let decoded = decode_gadget(section, address, 2, true);
+		let instructions = match &decoded {
+			Some((gadget, _)) => gadget.instructions(),
+			None => return false,
+		};
+		matches!(instructions[0].mnemonic(), Mnemonic::Ret | Mnemonic::Jmp)
+			&& instructions[1].mnemonic() == Mnemonic::Int3
+	})
+}
+
 pub struct Disassembly<'b> {
 	section: &'b Section<'b>,
 	bytes: &'b [u8],
@@ -73,22 +179,101 @@ impl<'b> Disassembly<'b> {
 
 	pub fn file_offset(&self) -> usize { self.file_offset }
 
+	/// Marks the bytes which are an immediate or displacement in a linear disassembly from the start
+	/// of the section, tails decoded from these bytes are never executed as such when the code runs
+	/// from its real instruction boundaries
+	pub fn linear_operand_bytes(&self) -> Vec<bool> {
+		let mut operand = vec![false; self.bytes.len()];
+		let mut decoder = Disassembler::new(self.section.bitness(), self.bytes).decoder;
+		decoder.set_ip(self.file_offset as u64);
+		let mut instruction = Instruction::default();
+		while decoder.can_decode() {
+			let position = decoder.position();
+			decoder.decode_out(&mut instruction);
+			let offsets = decoder.get_constant_offsets(&instruction);
+			let mut mark = |offset: usize, size: usize| {
+				let start = (position + offset).min(operand.len());
+				let end = (start + size).min(operand.len());
+				operand[start..end].iter_mut().for_each(|b| *b = true);
+			};
+			if offsets.has_displacement() {
+				mark(offsets.displacement_offset(), offsets.displacement_size());
+			}
+			if offsets.has_immediate() {
+				mark(offsets.immediate_offset(), offsets.immediate_size());
+			}
+			if offsets.has_immediate2() {
+				mark(offsets.immediate_offset2(), offsets.immediate_size2());
+			}
+		}
+		operand
+	}
+
+	/// Marks the offsets where instructions start in a linear disassembly from the start of the section
+	pub fn linear_instruction_starts(&self) -> Vec<bool> {
+		let mut starts = vec![false; self.bytes.len()];
+		let mut decoder = Disassembler::new(self.section.bitness(), self.bytes).decoder;
+		let mut instruction = Instruction::default();
+		while decoder.can_decode() {
+			starts[decoder.position()] = true;
+			decoder.decode_out(&mut instruction);
+		}
+		starts
+	}
+
+	/// Whether the instruction at `offset` begins with prefix bytes borrowed from a different
+	/// instruction in the linear disassembly, ie. its opcode or one of its prefixes starts another
+	/// instruction there
+	pub fn has_orphan_prefix(&self, linear_starts: &[bool], offset: usize) -> bool {
+		if linear_starts.get(offset).copied().unwrap_or(true) {
+			return false;
+		}
+		let rex = matches!(self.section.bitness(), Bitness::Bits64);
+		let prefixes = self.bytes[offset..]
+			.iter()
+			.take(MAX_INSTRUCTION_LENGTH - 1)
+			.take_while(|b| {
+				matches!(b, 0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3)
+					|| (rex && (0x40..=0x4f).contains(*b))
+			})
+			.count();
+		(offset + 1..=offset + prefixes).any(|i| linear_starts.get(i).copied().unwrap_or(false))
+	}
+
 	pub fn instruction(&self, index: usize) -> Option<&Instruction> { self.instructions.get(index) }
 
+	/// Whether an indirect branch could land on the instruction at `offset` with CET enforced, ie. it
+	/// is an `endbr` or directly follows one
+	pub fn at_landing_pad(&self, offset: usize) -> bool {
+		let bitness = self.section.bitness();
+		let is_pad = |i: &Instruction| is_landing_pad(i, bitness);
+		self.instructions.get(offset).is_some_and(is_pad)
+			|| offset
+				.checked_sub(4)
+				.and_then(|start| self.instructions.get(start))
+				.is_some_and(|i| is_pad(i) && i.len() == 4)
+	}
+
+	#[allow(clippy::too_many_arguments)]
 	pub fn is_tail_at(
         &self,
         index: usize,
         rop: bool,
-        sys: bool,
+        iret: bool,
+        sysret: bool,
+        sysexit: bool,
+        syscall: bool,
         jop: bool,
+        cop: bool,
+        direct: bool,
         noisy: bool,
         ret_thunk: Option<u64>,
-        thunks: &Vec<(String, Option<u64>)>,
-        jump_thunks: &Vec<(String, Option<u64>)>,
-        call_thunks: &Vec<(String, Option<u64>)>
+        thunks: &[(String, Option<u64>)],
+        jump_thunks: &[(String, Option<u64>)],
+        call_thunks: &[(String, Option<u64>)]
     ) -> bool {
 		let instruction = self.instructions[index];
-		is_gadget_tail(&instruction, rop, sys, jop, noisy, ret_thunk, thunks, jump_thunks, call_thunks)
+		is_gadget_tail(&instruction, rop, iret, sysret, sysexit, syscall, jop, cop, direct, noisy, ret_thunk, thunks, jump_thunks, call_thunks)
 	}
 
 	pub fn gadgets_from_tail(
@@ -97,7 +282,7 @@ impl<'b> Disassembly<'b> {
 		max_instructions: usize,
 		noisy: bool,
 		uniq: bool,
-	) -> GadgetIterator {
+	) -> GadgetIterator<'_> {
 		assert!(max_instructions > 0);
 		let start_index =
 			tail_index.saturating_sub((max_instructions - 1) * MAX_INSTRUCTION_LENGTH);
@@ -113,4 +298,46 @@ impl<'b> Disassembly<'b> {
 			start_index,
 		)
 	}
+
+	/// Lazily yields every gadget in the section with their addresses, tail by tail in address
+	/// order, using the default tail rules: every kind of return, syscall and indirect branch with no
+	/// thunks. The CLI scans in parallel instead, this is for library users filtering as they go
+	pub fn gadget_iter(
+		&self,
+		max_instructions: usize,
+		noisy: bool,
+		uniq: bool,
+	) -> impl Iterator<Item = (Gadget, usize)> + '_ {
+		(0..self.instructions.len())
+			.filter(move |index| {
+				let instruction = &self.instructions[*index];
+				is_gadget_tail(
+					instruction, true, true, true, true, true, true, true, false, noisy, None, &[], &[], &[],
+				)
+			})
+			.flat_map(move |tail| self.gadgets_from_tail(tail, max_instructions, noisy, uniq))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::binary::Binary;
+
+	#[test]
+	fn ret_in_immediate_is_an_operand_byte() {
+		// mov eax, 0xc3; ret
+		let binary = Binary::from_reader(&[0xb8, 0xc3, 0x00, 0x00, 0x00, 0xc3][..]).unwrap();
+		let sections = binary.sections(Some(true), Some(Bitness::Bits64), &[]).unwrap();
+		let dis = Disassembly::new(&sections[0]).unwrap();
+		let operand = dis.linear_operand_bytes();
+		assert_eq!(operand, [false, true, true, true, true, false]);
+		// Both c3 bytes decode as a `ret`, only the real one survives the filter
+		let rets = (0..dis.bytes().len())
+			.filter(|offset| dis.instruction(*offset).unwrap().mnemonic() == Mnemonic::Ret)
+			.collect::<Vec<_>>();
+		assert_eq!(rets, [1, 5]);
+		let tails = rets.into_iter().filter(|offset| !operand[*offset]).collect::<Vec<_>>();
+		assert_eq!(tails, [5]);
+	}
 }
@@ -1,3 +1,5 @@
+use serde::Serialize;
+use std::io::ErrorKind;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -13,3 +15,48 @@ pub enum Error {
 	#[error("unsupported format or architecture")]
 	Unsupported,
 }
+
+impl Error {
+	/// Stable identifier for the kind of error, for tools which need to tell errors apart
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Self::IoErr(e) => io_kind(e),
+			Self::GoblinErr(_) => "malformed-binary",
+			Self::ParseErr => "parse",
+			Self::Unsupported => "unsupported",
+		}
+	}
+}
+
+fn io_kind(e: &std::io::Error) -> &'static str {
+	match e.kind() {
+		ErrorKind::NotFound => "file-not-found",
+		ErrorKind::PermissionDenied => "permission-denied",
+		_ => "io",
+	}
+}
+
+/// Serializable form of an error, eg. `{"error": "unable to parse binary", "kind": "parse"}`
+#[derive(Serialize, Debug)]
+pub struct ErrorReport {
+	pub error: String,
+	pub kind: &'static str,
+}
+
+impl From<&Error> for ErrorReport {
+	fn from(e: &Error) -> Self {
+		Self {
+			error: e.to_string(),
+			kind: e.kind(),
+		}
+	}
+}
+
+impl From<&std::io::Error> for ErrorReport {
+	fn from(e: &std::io::Error) -> Self {
+		Self {
+			error: e.to_string(),
+			kind: io_kind(e),
+		}
+	}
+}
@@ -20,6 +20,9 @@ impl ColourFormatter {
 	pub fn new() -> Self { Self::default() }
 
 	pub fn clear(&mut self) { self.output.clear() }
+
+	/// The formatted text without any colour codes
+	pub fn plain(&self) -> String { self.output.iter().map(|s| &**s).collect() }
 }
 
 impl FormatterOutput for ColourFormatter {
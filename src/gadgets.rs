@@ -1,50 +1,694 @@
-use crate::rules::{
-	is_base_pivot_head, is_rop_gadget_head, is_stack_pivot_head, is_stack_pivot_tail,
+use crate::{
+	binary::Bitness,
+	rules::{
+		bit_op, has_memory_destination, immediate_load, indirect_branch_registers,
+		indirect_branch_slot, is_base_pivot_head, is_cmov, is_prologue, is_rop_gadget_head,
+		is_stack_pivot_head, is_stack_pivot_tail, memory_load, memory_write, pivot_kind,
+		pushed_register, read_registers, register_move, ret_immediate, stack_effect, stack_leak,
+		stack_load, string_op_registers, used_registers, writes_memory, written_registers,
+		zeroed_register, MemoryWrite, PivotKind,
+	},
 };
-use iced_x86::{Formatter, FormatterOutput, FormatterTextKind, Instruction};
-use std::hash::Hash;
+use iced_x86::{
+	CodeSize, Formatter, FormatterOutput, FormatterTextKind, Instruction, Mnemonic, OpKind, Register,
+};
+use serde::{Deserialize, Serialize};
+use std::{hash::Hash, str::FromStr};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HexStyle {
+	/// `0x1a2b`
+	#[default]
+	Lower,
+	/// `0x1A2B`
+	Upper,
+	/// `1A2Bh`
+	Masm,
+}
+
+impl FromStr for HexStyle {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"lower" => Ok(Self::Lower),
+			"upper" => Ok(Self::Upper),
+			"masm" => Ok(Self::Masm),
+			_ => Err(format!("unknown hex style `{}`, expected lower, upper or masm", s)),
+		}
+	}
+}
+
+impl HexStyle {
+	/// Formats a value the same way the instruction formatter writes immediates and addresses
+	pub fn format_value(&self, value: u64) -> String {
+		match self {
+			Self::Lower => format!("{:#x}", value),
+			Self::Upper => format!("0x{:X}", value),
+			Self::Masm => Self::masm(format!("{:X}", value)),
+		}
+	}
+
+	/// Formats a gadget address, zero padded for alignment
+	pub fn format_address(&self, address: usize) -> String {
+		match self {
+			Self::Lower => format!("{:#010x}", address),
+			Self::Upper => format!("0x{:08X}", address),
+			Self::Masm => Self::masm(format!("{:08X}", address)),
+		}
+	}
 
-#[derive(Debug, Eq, Hash, PartialEq)]
+	// MASM needs a leading zero so hex numbers aren't mistaken for identifiers
+	fn masm(digits: String) -> String {
+		if digits.starts_with(|c: char| c.is_ascii_alphabetic()) {
+			format!("0{}h", digits)
+		}
+		else {
+			format!("{}h", digits)
+		}
+	}
+}
+
+/// Where a gadget gets the value it leaves in a register, see `Gadget::register_source`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterSource {
+	/// Popped off the stack, so controlled by the chain
+	Stack,
+	/// Always this constant
+	Constant(u64),
+}
+
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub struct Gadget {
 	instructions: Vec<Instruction>,
+	/// Depends on `--nouniq`, so it isn't saved with the gadget, see `with_uniq`
+	#[serde(skip)]
 	unique_id: usize,
 }
 
+/// Parses a line of ropr's text output, `0x00001234: pop rdi; ret;`, into the gadget address and
+/// its instructions. Colour codes are ignored, the address may be in any `--hex-style`
+pub fn parse_gadget_line(line: &str) -> Option<(usize, Vec<String>)> {
+	let line = strip_ansi(line);
+	let (address, instructions) = line.split_once(": ")?;
+	// `--symbols` follows the address with the symbol it's in, eg. `0x00001234 <main+0x10>`
+	let address = address.split_once(" <").map_or(address, |(address, _)| address).trim();
+	let address = match (address.strip_prefix("0x"), address.strip_suffix('h')) {
+		(Some(hex), _) | (None, Some(hex)) => usize::from_str_radix(hex, 16).ok()?,
+		(None, None) => return None,
+	};
+	// Annotations such as `--tag-tail-rule`'s `[ret]` follow the last instruction and may contain `;`
+	let instructions = match instructions.find("; [") {
+		Some(end) => &instructions[..end + 1],
+		None => instructions,
+	};
+	let instructions = instructions
+		.split(';')
+		.map(str::trim)
+		// Skip annotations such as `--tag-tail-rule`'s `[ret]`
+		.filter(|i| !i.is_empty() && !i.starts_with('['))
+		.map(String::from)
+		.collect::<Vec<_>>();
+	if instructions.is_empty() {
+		return None;
+	}
+	Some((address, instructions))
+}
+
+fn strip_ansi(line: &str) -> String {
+	let mut stripped = String::with_capacity(line.len());
+	let mut chars = line.chars();
+	while let Some(c) = chars.next() {
+		if c == '\x1b' {
+			// Skip the escape sequence up to and including its final letter
+			for c in chars.by_ref() {
+				if c.is_ascii_alphabetic() {
+					break;
+				}
+			}
+		}
+		else {
+			stripped.push(c);
+		}
+	}
+	stripped
+}
+
 impl Gadget {
+	pub(crate) fn new(instructions: Vec<Instruction>, unique_id: usize) -> Self {
+		Self {
+			instructions,
+			unique_id,
+		}
+	}
+
+	/// Identifies a gadget loaded from a dump like a scanned one, by its address unless `uniq`
+	pub fn with_uniq(mut self, uniq: bool, address: usize) -> Self {
+		self.unique_id = if uniq { 0 } else { address };
+		self
+	}
+
 	pub fn instructions(&self) -> &[Instruction] { &self.instructions }
 
+	/// The mode the gadget was decoded in
+	pub fn bitness(&self) -> Bitness {
+		match self.instructions.first().map(|i| i.code_size()) {
+			Some(CodeSize::Code64) => Bitness::Bits64,
+			Some(CodeSize::Code16) => Bitness::Bits16,
+			_ => Bitness::Bits32,
+		}
+	}
+
+	/// Offset of the tail instruction from the start of the gadget
+	pub fn tail_offset(&self) -> usize {
+		match self.instructions.as_slice() {
+			[h @ .., _] => h.iter().map(|i| i.len()).sum(),
+			[] => 0,
+		}
+	}
+
+	/// Encoded length of the gadget in bytes, tail included
+	pub fn byte_len(&self) -> usize { self.instructions.iter().map(|i| i.len()).sum() }
+
 	pub fn is_stack_pivot(&self, ret_thunk: Option<u64>) -> bool {
 		match self.instructions.as_slice() {
 			[] => false,
 			[t] => is_stack_pivot_tail(t, ret_thunk),
-			[h @ .., _] => h.iter().any(is_stack_pivot_head),
+			[h @ .., _] => h.iter().any(|i| is_stack_pivot_head(i, self.bitness())),
+		}
+	}
+
+	/// Classifies the first stack pivot in the gadget body which has a more specific kind
+	pub fn pivot_kind(&self) -> Option<PivotKind> {
+		match self.instructions.as_slice() {
+			[] | [_] => None,
+			[h @ .., _] => h.iter().find_map(pivot_kind),
 		}
 	}
 
 	pub fn is_base_pivot(&self) -> bool {
 		match self.instructions.as_slice() {
 			[] | [_] => false,
-			[h @ .., _] => h.iter().any(is_base_pivot_head),
+			[h @ .., _] => h.iter().any(|i| is_base_pivot_head(i, self.bitness())),
 		}
 	}
 
-	pub fn format_instruction(&self, output: &mut impl FormatterOutput) {
-		let mut formatter = iced_x86::IntelFormatter::new();
-		let options = iced_x86::Formatter::options_mut(&mut formatter);
-		options.set_hex_prefix("0x");
-		options.set_hex_suffix("");
-		options.set_space_after_operand_separator(true);
-		options.set_branch_leading_zeroes(false);
-		options.set_uppercase_hex(false);
-		options.set_rip_relative_addresses(true);
-		// Write instructions
-		let mut instructions = self.instructions.iter().peekable();
-		while let Some(i) = instructions.next() {
-			formatter.format(i, output);
-			output.write(";", FormatterTextKind::Text);
-			if instructions.peek().is_some() {
-				output.write(" ", FormatterTextKind::Text);
+	/// Finds a `mov reg, [rsp/rbp+disp]` ahead of a `ret`, returning the loaded register and the
+	/// stack displacement it is loaded from
+	pub fn load_from_stack(&self, ret_thunk: Option<u64>) -> Option<(Register, i64)> {
+		match self.instructions.as_slice() {
+			[h @ .., t] if is_stack_pivot_tail(t, ret_thunk) => h.iter().find_map(stack_load),
+			_ => None,
+		}
+	}
+
+	pub fn is_syscall(&self, ret_thunk: Option<u64>) -> bool {
+		match self.instructions.as_slice() {
+			[h @ .., t] if is_stack_pivot_tail(t, ret_thunk) => {
+				h.iter().any(|i| i.mnemonic() == Mnemonic::Syscall)
+			}
+			_ => false,
+		}
+	}
+
+	pub fn ends_in_ret(&self, ret_thunk: Option<u64>) -> bool {
+		self.instructions
+			.last()
+			.is_some_and(|t| is_stack_pivot_tail(t, ret_thunk))
+	}
+
+	/// The bytes a `ret imm16` tail releases after the return address, which a chain has to pad
+	pub fn ret_immediate(&self) -> Option<u16> { self.instructions.last().and_then(ret_immediate) }
+
+	/// Whether the gadget ends in a `jmp` or `call` rather than a `ret` or return thunk
+	pub fn ends_in_branch(&self, ret_thunk: Option<u64>) -> bool {
+		self.instructions.last().is_some_and(|t| {
+			matches!(t.mnemonic(), Mnemonic::Jmp | Mnemonic::Call) && !is_stack_pivot_tail(t, ret_thunk)
+		})
+	}
+
+	/// Checks for a `pop reg` which is not clobbered by the remainder of the gadget before a `ret`
+	pub fn pops_register(&self, reg: Register, ret_thunk: Option<u64>) -> bool {
+		let reg = reg.full_register();
+		match self.instructions.as_slice() {
+			[h @ .., t] if is_stack_pivot_tail(t, ret_thunk) => h
+				.iter()
+				.rposition(|i| i.mnemonic() == Mnemonic::Pop && i.op0_register().full_register() == reg)
+				.is_some_and(|pos| {
+					!h[pos + 1..]
+						.iter()
+						.flat_map(written_registers)
+						.any(|r| r == reg)
+				}),
+			_ => false,
+		}
+	}
+
+	/// How a gadget ending in `ret` leaves `reg` set: popped off the stack or loaded with a constant,
+	/// eg. `pop rax; ret`, `mov eax, 0x3b; ret` or `xor eax, eax; ret`. Only the last write to `reg`
+	/// counts, and any other write clobbering it afterwards rules the gadget out
+	pub fn register_source(&self, reg: Register, ret_thunk: Option<u64>) -> Option<RegisterSource> {
+		let reg = reg.full_register();
+		let body = match self.instructions.as_slice() {
+			[h @ .., t] if is_stack_pivot_tail(t, ret_thunk) => h,
+			_ => return None,
+		};
+		let last_write = body
+			.iter()
+			.rposition(|i| written_registers(i).contains(&reg))
+			.map(|pos| &body[pos])?;
+		if last_write.mnemonic() == Mnemonic::Pop && last_write.op0_register().full_register() == reg {
+			return Some(RegisterSource::Stack);
+		}
+		match (immediate_load(last_write), zeroed_register(last_write)) {
+			(Some((dest, value)), _) if dest == reg => Some(RegisterSource::Constant(value)),
+			(_, Some(dest)) if dest == reg => Some(RegisterSource::Constant(0)),
+			_ => None,
+		}
+	}
+
+	/// Finds a `mov reg, rsp` or `lea reg, [rsp+disp]` ahead of a `ret` whose register survives to
+	/// the end of the gadget, returning the register holding the leaked stack address
+	pub fn leaks_rsp(&self, ret_thunk: Option<u64>) -> Option<Register> {
+		match self.instructions.as_slice() {
+			[h @ .., t] if is_stack_pivot_tail(t, ret_thunk) => {
+				h.iter().enumerate().rev().find_map(|(pos, i)| {
+					let reg = stack_leak(i)?;
+					let clobbered = h[pos + 1..]
+						.iter()
+						.flat_map(written_registers)
+						.any(|r| r == reg);
+					(!clobbered).then_some(reg)
+				})
 			}
+			_ => None,
+		}
+	}
+
+	/// Registers restored all at once before a `ret`, either by `popa`/`popad` or by a run of at least
+	/// `min_pops` consecutive `pop reg` instructions leading up to the tail
+	pub fn restored_registers(
+		&self,
+		min_pops: usize,
+		ret_thunk: Option<u64>,
+	) -> Option<Vec<Register>> {
+		let body = match self.instructions.as_slice() {
+			[h @ .., t] if is_stack_pivot_tail(t, ret_thunk) => h,
+			_ => return None,
+		};
+		let popa = body
+			.iter()
+			.rev()
+			.find(|i| matches!(i.mnemonic(), Mnemonic::Popa | Mnemonic::Popad));
+		if let Some(popa) = popa {
+			// The stack pointer's slot is skipped
+			let registers = match popa.mnemonic() {
+				Mnemonic::Popad => [
+					Register::EDI,
+					Register::ESI,
+					Register::EBP,
+					Register::EBX,
+					Register::EDX,
+					Register::ECX,
+					Register::EAX,
+				],
+				_ => [
+					Register::DI,
+					Register::SI,
+					Register::BP,
+					Register::BX,
+					Register::DX,
+					Register::CX,
+					Register::AX,
+				],
+			};
+			return Some(registers.to_vec());
+		}
+		let pops = body
+			.iter()
+			.rev()
+			.take_while(|i| i.mnemonic() == Mnemonic::Pop && i.op0_kind() == OpKind::Register)
+			.map(|i| i.op0_register())
+			.collect::<Vec<_>>();
+		(pops.len() >= min_pops).then(|| pops.into_iter().rev().collect())
+	}
+
+	/// Finds a `push reg` ahead of a `ret`, leaving the register's value in a stack slot
+	pub fn pushed_register(&self, ret_thunk: Option<u64>) -> Option<Register> {
+		match self.instructions.as_slice() {
+			[h @ .., t] if is_stack_pivot_tail(t, ret_thunk) => h.iter().find_map(pushed_register),
+			_ => None,
+		}
+	}
+
+	pub fn starts_with_prologue(&self) -> bool {
+		match self.instructions.as_slice() {
+			[h @ .., _] => is_prologue(h),
+			[] => false,
+		}
+	}
+
+	/// Whether any instruction is a conditional move
+	pub fn has_cmov(&self) -> bool { self.instructions.iter().any(is_cmov) }
+
+	/// Finds `cld`/`std` and `movs`/`stos` string copies in the gadget body, returning the registers
+	/// the string copies use
+	pub fn string_op_registers(&self) -> Option<Vec<Register>> {
+		let body = match self.instructions.as_slice() {
+			[h @ .., _] => h,
+			[] => return None,
+		};
+		let mut ops = body.iter().filter_map(string_op_registers).peekable();
+		ops.peek()?;
+		let mut registers = ops.flatten().collect::<Vec<_>>();
+		registers.sort_unstable();
+		registers.dedup();
+		Some(registers)
+	}
+
+	/// A quick check, not a solver, for whether the gadget loads `reg` from the stack or another
+	/// register: its first instruction other than `nop` is `pop reg` or `mov reg, reg2` and nothing
+	/// after it writes `reg` again. Values computed any other way aren't recognised
+	pub fn sets_register(&self, reg: Register) -> bool {
+		let reg = reg.full_register();
+		let mut instructions = self.instructions.iter().skip_while(|i| i.mnemonic() == Mnemonic::Nop);
+		let loads = match instructions.next() {
+			Some(first) if first.mnemonic() == Mnemonic::Pop => {
+				first.op0_kind() == OpKind::Register && first.op0_register().full_register() == reg
+			}
+			Some(first) => register_move(first).is_some_and(|(dest, _)| dest == reg),
+			None => false,
+		};
+		// The loading instruction can't be the tail
+		let mut rest = instructions.peekable();
+		loads && rest.peek().is_some() && !rest.flat_map(written_registers).any(|r| r == reg)
+	}
+
+	/// Whether any instruction writes one of `registers`, partial writes such as `edi` count as
+	/// writing `rdi`. The stack pointer only counts as clobbered when it is moved by something other
+	/// than a constant, eg. `pop rsp` or `leave`, as every gadget advances it with `pop` and `ret`
+	pub fn clobbers(&self, registers: &[Register]) -> bool {
+		let registers = registers.iter().map(|r| r.full_register()).collect::<Vec<_>>();
+		if registers.contains(&Register::RSP) && self.stack_delta().is_none() {
+			return true;
+		}
+		self.instructions
+			.iter()
+			.flat_map(written_registers)
+			.any(|r| r != Register::RSP && registers.contains(&r))
+	}
+
+	/// Number of distinct general purpose registers read or written, not counting the stack pointer
+	/// which every gadget uses
+	pub fn register_count(&self) -> usize {
+		let mut registers = self
+			.instructions
+			.iter()
+			.flat_map(used_registers)
+			.filter(|r| *r != Register::RSP)
+			.collect::<Vec<_>>();
+		registers.sort_unstable();
+		registers.dedup();
+		registers.len()
+	}
+
+	/// The memory slot the gadget's tail branches through, when it is at a fixed address
+	pub fn branch_slot(&self) -> Option<u64> { self.instructions.last().and_then(indirect_branch_slot) }
+
+	/// Finds a `mov [base+index*scale], value` write in the gadget body
+	pub fn memory_write(&self) -> Option<MemoryWrite> {
+		match self.instructions.as_slice() {
+			[h @ .., _] => h.iter().find_map(memory_write),
+			[] => None,
+		}
+	}
+
+	/// Whether running the gadget a second time leaves registers and memory as the first run did,
+	/// judged by the body (everything before the tail):
+	/// - it writes no memory, including pushes
+	/// - no general purpose register it writes is also read by it, eg. `add rax, rbx` or
+	///   `mov rax, rbx; mov rbx, 1`
+	/// - it doesn't both read and modify flags, eg. `cmp rax, rbx; cmove rax, rcx`
+	///
+	/// The stack pointer is ignored as every gadget advances it, so values popped off the stack count
+	/// as fresh inputs. Loads from memory are allowed as the gadget can't have changed it
+	pub fn is_idempotent(&self) -> bool {
+		let body = match self.instructions.as_slice() {
+			[h @ .., _] => h,
+			[] => return false,
+		};
+		if body.iter().any(writes_memory) {
+			return false;
+		}
+		let read = body
+			.iter()
+			.flat_map(read_registers)
+			.filter(|r| *r != Register::RSP)
+			.collect::<Vec<_>>();
+		let clobbers_input = body
+			.iter()
+			.flat_map(written_registers)
+			.filter(|r| r.is_gpr() && *r != Register::RSP)
+			.any(|r| read.contains(&r));
+		let reads_flags = body.iter().any(|i| i.rflags_read() != 0);
+		let modifies_flags = body.iter().any(|i| i.rflags_modified() != 0);
+		!(clobbers_input || reads_flags && modifies_flags)
+	}
+
+	/// Whether any instruction writes to a memory destination operand, whatever its addressing form,
+	/// eg. `mov qword ptr [rsp], rax` or `add [rdi+rcx*8], edx`
+	pub fn writes_memory(&self) -> bool { self.instructions.iter().any(has_memory_destination) }
+
+	/// Finds a `mov reg, [base+disp]` load in the gadget body, returning the loaded register, base
+	/// and displacement
+	pub fn memory_load(&self) -> Option<(Register, Register, i64)> {
+		match self.instructions.as_slice() {
+			[h @ .., _] => h.iter().find_map(memory_load),
+			[] => None,
+		}
+	}
+
+	/// Finds an `and`/`or`/`shl`/`shr`/`sar` of `reg` by an immediate in the gadget body, returning the
+	/// operation and the immediate
+	pub fn bit_op(&self, reg: Register) -> Option<(Mnemonic, u64)> {
+		let reg = reg.full_register();
+		match self.instructions.as_slice() {
+			[h @ .., _] => h
+				.iter()
+				.filter_map(bit_op)
+				.find(|(_, r, _)| *r == reg)
+				.map(|(op, _, immediate)| (op, immediate)),
+			[] => None,
+		}
+	}
+
+	/// The net change the gadget makes to the stack pointer, including its tail, `None` when this
+	/// can't be determined statically
+	pub fn stack_delta(&self) -> Option<i64> { self.instructions.iter().map(stack_effect).sum() }
+
+	/// A short summary of what the gadget does, eg. `pops rdi, rsi; returns (stack +0x18)`
+	pub fn describe(&self, ret_thunk: Option<u64>, hex_style: HexStyle) -> String {
+		let (body, tail) = match self.instructions.as_slice() {
+			[h @ .., t] => (h, t),
+			[] => return String::new(),
+		};
+		let name = |r: Register| format!("{:?}", r).to_lowercase();
+		let mut parts: Vec<String> = Vec::new();
+		let mut pops: Vec<String> = Vec::new();
+		for instr in body {
+			if instr.mnemonic() == Mnemonic::Pop && instr.op0_kind() == OpKind::Register {
+				pops.push(name(instr.op0_register()));
+				continue;
+			}
+			if !pops.is_empty() {
+				parts.push(format!("pops {}", pops.join(", ")));
+				pops.clear();
+			}
+			let part = if let Some(kind) = pivot_kind(instr) {
+				match kind {
+					PivotKind::Fixed(address) => {
+						Some(format!("fixed-pivot {}", hex_style.format_value(address)))
+					}
+					kind => Some(kind.to_string()),
+				}
+			}
+			else if register_move(instr).is_some() {
+				Some(format!("{} = {}", name(instr.op0_register()), name(instr.op1_register())))
+			}
+			else if let Some((dest, _, _)) = memory_load(instr) {
+				Some(format!("{} = {}", name(dest), format_operand(instr, 1, hex_style)))
+			}
+			else if let Some(write) = memory_write(instr) {
+				Some(format!("{} = {}", format_operand(instr, 0, hex_style), name(write.value)))
+			}
+			// Pivots without a simpler summary, eg. `lea rsp, [rax+8]` or `add rsp, [rbx]`
+			else if is_stack_pivot_head(instr, self.bitness()) {
+				let mut formatted = String::new();
+				format_instructions(std::slice::from_ref(instr), &mut formatted, hex_style, false);
+				Some(format!("pivots with {}", formatted.trim_end_matches(';')))
+			}
+			else if let Some(reg) = pushed_register(instr) {
+				Some(format!("pushes {}", name(reg.full_register())))
+			}
+			else if instr.mnemonic() == Mnemonic::Syscall {
+				Some(String::from("syscall"))
+			}
+			else {
+				None
+			};
+			parts.extend(part);
+		}
+		if !pops.is_empty() {
+			parts.push(format!("pops {}", pops.join(", ")));
+		}
+		let tail = if let Some(released) = ret_immediate(tail) {
+			format!("returns skipping {}", hex_style.format_value(released.into()))
+		}
+		else if is_stack_pivot_tail(tail, ret_thunk) {
+			String::from("returns")
+		}
+		else {
+			match tail.mnemonic() {
+				Mnemonic::Jmp => format!("jumps to {}", format_operand(tail, 0, hex_style)),
+				Mnemonic::Call => format!("calls {}", format_operand(tail, 0, hex_style)),
+				mnemonic => format!("{:?}", mnemonic).to_lowercase(),
+			}
+		};
+		let stack = match self.stack_delta() {
+			Some(delta) if delta < 0 => {
+				format!("stack -{}", hex_style.format_value(delta.unsigned_abs()))
+			}
+			Some(delta) => format!("stack +{}", hex_style.format_value(delta as u64)),
+			None => String::from("stack unknown"),
+		};
+		parts.push(format!("{} ({})", tail, stack));
+		parts.join("; ")
+	}
+
+	/// Finds a `mov dest, src` between registers in the gadget body
+	pub fn register_move(&self) -> Option<(Register, Register)> {
+		match self.instructions.as_slice() {
+			[h @ .., _] => h.iter().find_map(register_move),
+			[] => None,
+		}
+	}
+
+	/// Finds a load into a register which is then written to memory, eg.
+	/// `mov rax, [rsi]; mov [rdi], rax`, returning the source and destination pointer registers
+	pub fn memory_copy(&self) -> Option<(Register, Register)> {
+		let body = match self.instructions.as_slice() {
+			[h @ .., _] => h,
+			[] => return None,
+		};
+		body.iter().enumerate().find_map(|(load_pos, load)| {
+			let (reg, src, _) = memory_load(load)?;
+			let reg = reg.full_register();
+			let rest = &body[load_pos + 1..];
+			let write_pos = rest.iter().position(|i| {
+				memory_write(i).is_some_and(|write| write.value.full_register() == reg)
+			})?;
+			// The copied value must survive until it is written
+			let clobbered = rest[..write_pos]
+				.iter()
+				.flat_map(written_registers)
+				.any(|r| r == reg);
+			let write = memory_write(&rest[write_pos])?;
+			let dest = match write.base {
+				Register::None => write.index,
+				base => base,
+			};
+			(!clobbered).then_some((src.full_register(), dest.full_register()))
+		})
+	}
+
+	/// A JOP dispatcher modifies a register in its body and then branches through that register,
+	/// e.g. `add rsi, 8; jmp [rsi]`
+	pub fn is_dispatcher(&self) -> bool {
+		match self.instructions.as_slice() {
+			[] | [_] => false,
+			[h @ .., t] => {
+				let targets = indirect_branch_registers(t);
+				!targets.is_empty()
+					&& h.iter()
+						.flat_map(written_registers)
+						.any(|r| targets.contains(&r))
+			}
+		}
+	}
+
+	/// Finds an indirect branch whose target register is loaded from memory earlier in the gadget,
+	/// e.g. `mov rax, [rbx+0x10]; jmp rax`, returning the pointer register and offset
+	pub fn vtable_load(&self) -> Option<(Register, i64)> {
+		match self.instructions.as_slice() {
+			[] | [_] => None,
+			[h @ .., t] => indirect_branch_registers(t).into_iter().find_map(|target| {
+				let load = h
+					.iter()
+					.rev()
+					.find(|i| written_registers(i).contains(&target))?;
+				match memory_load(load)? {
+					(reg0, base, displacement) if reg0.full_register() == target => {
+						Some((base, displacement))
+					}
+					_ => None,
+				}
+			}),
+		}
+	}
+
+	pub fn format_instruction(&self, output: &mut impl FormatterOutput) {
+		self.format_instruction_styled(output, HexStyle::default())
+	}
+
+	pub fn format_instruction_styled(&self, output: &mut impl FormatterOutput, hex_style: HexStyle) {
+		format_instructions(&self.instructions, output, hex_style, false)
+	}
+}
+
+/// The formatter settings gadgets are printed with, see `format_instructions`
+fn formatter(hex_style: HexStyle, resolve_rip: bool) -> iced_x86::IntelFormatter {
+	let mut formatter = iced_x86::IntelFormatter::new();
+	let options = iced_x86::Formatter::options_mut(&mut formatter);
+	match hex_style {
+		HexStyle::Lower | HexStyle::Upper => {
+			options.set_hex_prefix("0x");
+			options.set_hex_suffix("");
+		}
+		HexStyle::Masm => {
+			options.set_hex_prefix("");
+			options.set_hex_suffix("h");
+		}
+	}
+	options.set_space_after_operand_separator(true);
+	options.set_branch_leading_zeroes(false);
+	options.set_uppercase_hex(hex_style != HexStyle::Lower);
+	options.set_rip_relative_addresses(!resolve_rip);
+	formatter
+}
+
+fn format_operand(instr: &Instruction, operand: u32, hex_style: HexStyle) -> String {
+	let mut formatter = formatter(hex_style, false);
+	let mut formatted = String::new();
+	let _ = formatter.format_operand(instr, &mut formatted, operand);
+	formatted
+}
+
+/// Formats instructions the way gadgets are printed, `pop rdi; ret;`. With `resolve_rip` RIP-relative
+/// operands are shown as the absolute address they refer to, computed from each instruction's IP
+pub fn format_instructions(
+	instructions: &[Instruction],
+	output: &mut impl FormatterOutput,
+	hex_style: HexStyle,
+	resolve_rip: bool,
+) {
+	let mut formatter = formatter(hex_style, resolve_rip);
+	// Write instructions
+	let mut instructions = instructions.iter().peekable();
+	while let Some(i) = instructions.next() {
+		formatter.format(i, output);
+		output.write(";", FormatterTextKind::Text);
+		if instructions.peek().is_some() {
+			output.write(" ", FormatterTextKind::Text);
 		}
 	}
 }
@@ -58,6 +702,9 @@ pub struct GadgetIterator<'d> {
 	uniq: bool,
 	start_index: usize,
 	finished: bool,
+	/// Whether each head position can reach the tail, see `pruned`
+	reachable: Option<Vec<bool>>,
+	first_index: usize,
 }
 
 impl<'d> GadgetIterator<'d> {
@@ -79,7 +726,32 @@ impl<'d> GadgetIterator<'d> {
 			uniq,
 			start_index,
 			finished: false,
+			reachable: None,
+			first_index: start_index,
+		}
+	}
+
+	/// Works out up front which head positions can reach the tail, so positions whose walk runs into
+	/// an instruction that can never be part of a gadget are skipped rather than re-walked
+	pub fn pruned(mut self) -> Self {
+		let len = self.predecessors.len();
+		// Instructions needed to get from each position to the tail
+		let mut distance = vec![None; len + 1];
+		distance[len] = Some(0);
+		for index in (0..len).rev() {
+			let instruction = &self.predecessors[index];
+			if !is_rop_gadget_head(instruction, self.noisy) {
+				continue;
+			}
+			distance[index] = distance
+				.get(index + instruction.len())
+				.copied()
+				.flatten()
+				.map(|d| d + 1)
+				.filter(|d| *d < self.max_instructions);
 		}
+		self.reachable = Some(distance[..len].iter().map(Option::is_some).collect());
+		self
 	}
 }
 
@@ -91,6 +763,13 @@ impl Iterator for GadgetIterator<'_> {
 
 		'outer: while !self.predecessors.is_empty() {
 			instructions.clear();
+			if let Some(reachable) = &self.reachable {
+				if !reachable[self.start_index - self.first_index] {
+					self.predecessors = &self.predecessors[1..];
+					self.start_index += 1;
+					continue;
+				}
+			}
 			let len = self.predecessors.len();
 			let mut index = 0;
 			while index < len && instructions.len() < self.max_instructions - 1 {
@@ -151,3 +830,145 @@ impl Iterator for GadgetIterator<'_> {
 		None
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{binary::Binary, disassembler::Disassembly};
+	use iced_x86::{Decoder, DecoderOptions};
+	use rustc_hash::FxHashSet;
+
+	/// Scans `bytes` as raw 64-bit code, gadgets deduplicated by hashing as the CLI does
+	fn scan(bytes: &[u8], max_instructions: usize, uniq: bool) -> Vec<(Gadget, usize)> {
+		let binary = Binary::from_reader(bytes).unwrap();
+		let sections = binary.sections(Some(true), Some(Bitness::Bits64), &[]).unwrap();
+		let dis = Disassembly::new(&sections[0]).unwrap();
+		let mut seen = FxHashSet::default();
+		dis.gadget_iter(max_instructions, false, uniq)
+			.filter(|(gadget, _)| seen.insert(gadget.clone()))
+			.collect()
+	}
+
+	#[test]
+	fn consecutive_rets_are_separate_gadgets() {
+		for max_instructions in [1, 6] {
+			let gadgets = scan(&[0xc3, 0xc3, 0xc3], max_instructions, false);
+			let addresses = gadgets.iter().map(|(_, address)| *address).collect::<Vec<_>>();
+			assert_eq!(addresses, [0, 1, 2]);
+			assert_eq!(scan(&[0xc3, 0xc3, 0xc3], max_instructions, true).len(), 1);
+		}
+	}
+
+	#[test]
+	fn pruning_keeps_every_gadget() {
+		// pop rdi; pop rsi; ret, an invalid opcode, int3, hlt, lock add and xor eax, eax in a
+		// pseudo random order
+		let pieces: [&[u8]; 8] = [
+			&[0x5f],
+			&[0x5e],
+			&[0xc3],
+			&[0x06],
+			&[0xcc],
+			&[0xf4],
+			&[0xf0, 0x01, 0x07],
+			&[0x31, 0xc0],
+		];
+		let mut state = 0x2545f491u32;
+		let mut bytes = Vec::new();
+		while bytes.len() < 0x1000 {
+			state = state.wrapping_mul(1103515245).wrapping_add(12345);
+			bytes.extend(pieces[(state >> 16) as usize % pieces.len()]);
+		}
+		let binary = Binary::from_reader(bytes.as_slice()).unwrap();
+		let sections = binary.sections(Some(true), Some(Bitness::Bits64), &[]).unwrap();
+		let dis = Disassembly::new(&sections[0]).unwrap();
+		let tails = (0..bytes.len()).filter(|offset| bytes[*offset] == 0xc3);
+		for tail in tails {
+			for (max_instructions, noisy) in [(2, false), (6, false), (10, false), (10, true)] {
+				let gadgets = || dis.gadgets_from_tail(tail, max_instructions, noisy, false);
+				let pruned = gadgets().pruned().collect::<Vec<_>>();
+				assert_eq!(pruned, gadgets().collect::<Vec<_>>(), "tail at {:#x}", tail);
+			}
+		}
+	}
+
+	fn format(instructions: &[Instruction], resolve_rip: bool) -> String {
+		let mut formatted = String::new();
+		format_instructions(instructions, &mut formatted, HexStyle::Lower, resolve_rip);
+		formatted
+	}
+
+	#[test]
+	fn resolves_rip_relative_operands() {
+		// lea rax, [rip+0x10]; ret
+		let bytes = [0x48, 0x8d, 0x05, 0x10, 0x00, 0x00, 0x00, 0xc3];
+		let decoder = Decoder::with_ip(64, &bytes, 0x401000, DecoderOptions::NONE);
+		let instructions = decoder.into_iter().collect::<Vec<_>>();
+		assert_eq!(format(&instructions, false), "lea rax, [rip+0x10]; ret;");
+		assert_eq!(format(&instructions, true), "lea rax, [0x401017]; ret;");
+		// Scanned gadgets are decoded at their address, raw blobs start at 0
+		let (gadget, address) = scan(&bytes, 6, false).into_iter().find(|(_, a)| *a == 0).unwrap();
+		assert_eq!(gadget.instructions()[0].ip(), address as u64);
+		assert_eq!(format(gadget.instructions(), true), "lea rax, [0x17]; ret;");
+	}
+
+	/// A gadget of the instructions in `bytes` as 64-bit code
+	fn gadget(bytes: &[u8]) -> Gadget {
+		let decoder = Decoder::new(64, bytes, DecoderOptions::NONE);
+		Gadget::new(decoder.into_iter().collect(), 0)
+	}
+
+	#[test]
+	fn describes_in_the_hex_style() {
+		// pop rdi; pop rsi; ret
+		let pops = gadget(&[0x5f, 0x5e, 0xc3]);
+		assert_eq!(pops.describe(None, HexStyle::Lower), "pops rdi, rsi; returns (stack +0x18)");
+		assert_eq!(pops.describe(None, HexStyle::Masm), "pops rdi, rsi; returns (stack +18h)");
+		// mov rax, [rsi+0x1a]; jmp rax
+		let load = gadget(&[0x48, 0x8b, 0x46, 0x1a, 0xff, 0xe0]);
+		let described = load.describe(None, HexStyle::Upper);
+		assert_eq!(described, "rax = [rsi+0x1A]; jumps to rax (stack +0x0)");
+		// ret 0x10
+		let skip = gadget(&[0xc2, 0x10, 0x00]);
+		assert_eq!(skip.describe(None, HexStyle::Masm), "returns skipping 10h (stack +18h)");
+	}
+
+	#[test]
+	fn describes_pivots() {
+		// lea rsp, [rax+8]; ret
+		let lea = gadget(&[0x48, 0x8d, 0x60, 0x08, 0xc3]);
+		assert_eq!(
+			lea.describe(None, HexStyle::Lower),
+			"pivots with lea rsp, [rax+8]; returns (stack unknown)"
+		);
+		// add rsp, [rbx]; ret
+		let add = gadget(&[0x48, 0x03, 0x23, 0xc3]);
+		assert_eq!(
+			add.describe(None, HexStyle::Lower),
+			"pivots with add rsp, [rbx]; returns (stack unknown)"
+		);
+	}
+
+	#[test]
+	fn finds_memory_copy() {
+		// mov rax, [rsi]; mov [rdi], rax; ret
+		let copy = gadget(&[0x48, 0x8b, 0x06, 0x48, 0x89, 0x07, 0xc3]);
+		assert_eq!(copy.memory_copy(), Some((Register::RSI, Register::RDI)));
+		// mov eax, [rsi+8]; pop rbx; mov [rdx+rcx*8], rax; ret
+		let copy = gadget(&[0x8b, 0x46, 0x08, 0x5b, 0x48, 0x89, 0x04, 0xca, 0xc3]);
+		assert_eq!(copy.memory_copy(), Some((Register::RSI, Register::RDX)));
+	}
+
+	#[test]
+	fn memory_copy_needs_the_loaded_value() {
+		// mov rax, [rsi]; xor eax, eax; mov [rdi], rax; ret
+		let clobbered = gadget(&[0x48, 0x8b, 0x06, 0x31, 0xc0, 0x48, 0x89, 0x07, 0xc3]);
+		assert_eq!(clobbered.memory_copy(), None);
+		// mov rax, [rsi]; mov [rdi], rbx; ret
+		let other = gadget(&[0x48, 0x8b, 0x06, 0x48, 0x89, 0x1f, 0xc3]);
+		assert_eq!(other.memory_copy(), None);
+		// mov [rdi], rax; mov rax, [rsi]; ret
+		let reversed = gadget(&[0x48, 0x89, 0x07, 0x48, 0x8b, 0x06, 0xc3]);
+		assert_eq!(reversed.memory_copy(), None);
+	}
+}
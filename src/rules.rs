@@ -1,4 +1,9 @@
-use iced_x86::{Code, FlowControl, Instruction, Mnemonic, OpKind, Register};
+use crate::binary::Bitness;
+use iced_x86::{
+	Code, CodeSize, FlowControl, Instruction, InstructionInfoFactory, Mnemonic, OpAccess, OpKind,
+	Register,
+};
+use std::fmt::{self, Display, Formatter};
 
 fn is_ret(instr: &Instruction, ret_thunk: Option<u64>) -> bool {
     match instr.mnemonic() {
@@ -22,9 +27,9 @@ fn is_ret(instr: &Instruction, ret_thunk: Option<u64>) -> bool {
 fn is_target_thunk(
     instr: &Instruction,
     ret_thunk: Option<u64>,
-	thunks: &Vec<(String, Option<u64>)>,
-	jump_thunks: &Vec<(String, Option<u64>)>,
-	call_thunks: &Vec<(String, Option<u64>)>
+	thunks: &[(String, Option<u64>)],
+	jump_thunks: &[(String, Option<u64>)],
+	call_thunks: &[(String, Option<u64>)]
 ) -> bool {
     match instr.mnemonic() {
         Mnemonic::Jmp => {
@@ -33,7 +38,7 @@ fn is_target_thunk(
                     let target = instr.near_branch_target();
 
                     // check return_thunk first
-                    if ret_thunk.map_or(false, |addr| addr == target) {
+                    if ret_thunk == Some(target) {
                         return true;
                     }
 
@@ -55,76 +60,163 @@ fn is_target_thunk(
     }
 }
 
-fn is_sys(instr: &Instruction) -> bool {
+fn is_sys(instr: &Instruction, iret: bool, sysret: bool, sysexit: bool) -> bool {
 	match instr.mnemonic() {
-		Mnemonic::Iret | Mnemonic::Iretd | Mnemonic::Iretq => true,
-		Mnemonic::Sysret | Mnemonic::Sysretq | Mnemonic::Sysexit | Mnemonic::Sysexitq => true,
+		Mnemonic::Iret | Mnemonic::Iretd | Mnemonic::Iretq => iret,
+		Mnemonic::Sysret | Mnemonic::Sysretq => sysret,
+		Mnemonic::Sysexit | Mnemonic::Sysexitq => sysexit,
 		_ => false,
 	}
 }
 
-fn is_jop(instr: &Instruction, noisy: bool) -> bool {
+/// Entries into the kernel, `syscall`, `sysenter` and `int 0x80`
+fn is_syscall(instr: &Instruction) -> bool {
 	match instr.mnemonic() {
-		Mnemonic::Jmp => {
-			if noisy {
-				true
-			}
-			else {
-				match instr.op0_kind() {
-					OpKind::Register => true,
-					OpKind::Memory => !matches!(instr.memory_base(), Register::EIP | Register::RIP),
-					_ => false,
-				}
-			}
-		}
-		Mnemonic::Call => {
-			if noisy {
-				true
-			}
-			else {
-				match instr.op0_kind() {
-					OpKind::Register => true,
-					OpKind::Memory => !matches!(instr.memory_base(), Register::EIP | Register::RIP),
-					_ => false,
-				}
-			}
-		}
+		Mnemonic::Syscall | Mnemonic::Sysenter => true,
+		Mnemonic::Int => instr.immediate8() == 0x80,
 		_ => false,
 	}
 }
 
+// Register or memory targets, all targets with `noisy`
+fn is_indirect_target(instr: &Instruction, noisy: bool) -> bool {
+	if noisy {
+		return true;
+	}
+	match instr.op0_kind() {
+		OpKind::Register => true,
+		OpKind::Memory => !matches!(instr.memory_base(), Register::EIP | Register::RIP),
+		_ => false,
+	}
+}
+
+/// `jmp` ended dispatch gadgets
+fn is_jop(instr: &Instruction, noisy: bool) -> bool {
+	instr.mnemonic() == Mnemonic::Jmp && is_indirect_target(instr, noisy)
+}
+
+/// `call` ended (call-oriented) dispatch gadgets
+fn is_cop(instr: &Instruction, noisy: bool) -> bool {
+	instr.mnemonic() == Mnemonic::Call && is_indirect_target(instr, noisy)
+}
+
+/// A `jmp` or `call` to a constant address
+fn is_direct_branch(instr: &Instruction) -> bool {
+	matches!(instr.mnemonic(), Mnemonic::Jmp | Mnemonic::Call)
+		&& matches!(
+			instr.op0_kind(),
+			OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64
+		)
+}
+
+/// The target of a direct `jmp` or `call`
+pub fn direct_branch_target(instr: &Instruction) -> Option<u64> {
+	is_direct_branch(instr).then(|| instr.near_branch_target())
+}
+
 fn is_invalid(instr: &Instruction) -> bool { matches!(instr.code(), Code::INVALID) }
 
+/// The rule which accepted a gadget tail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TailRule {
+	Ret,
+	Thunk,
+	SysIret,
+	SysSysret,
+	SysSysexit,
+	Syscall,
+	JopJmp,
+	JopCall,
+	Direct,
+}
+
+impl Display for TailRule {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		let tag = match self {
+			Self::Ret => "ret",
+			Self::Thunk => "thunk",
+			Self::SysIret => "sys-iret",
+			Self::SysSysret => "sys-sysret",
+			Self::SysSysexit => "sys-sysexit",
+			Self::Syscall => "syscall",
+			Self::JopJmp => "jop-jmp",
+			Self::JopCall => "jop-call",
+			Self::Direct => "direct",
+		};
+		write!(f, "{}", tag)
+	}
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn is_gadget_tail(
     instr: &Instruction,
     rop: bool,
-    sys: bool,
+    iret: bool,
+    sysret: bool,
+    sysexit: bool,
+    syscall: bool,
     jop: bool,
+    cop: bool,
+    direct: bool,
     noisy: bool,
     ret_thunk: Option<u64>,
-    thunks: &Vec<(String, Option<u64>)>,
-    jump_thunks: &Vec<(String, Option<u64>)>,
-    call_thunks: &Vec<(String, Option<u64>)>
+    thunks: &[(String, Option<u64>)],
+    jump_thunks: &[(String, Option<u64>)],
+    call_thunks: &[(String, Option<u64>)]
 ) -> bool {
+	gadget_tail_rule(instr, rop, iret, sysret, sysexit, syscall, jop, cop, direct, noisy, ret_thunk, thunks, jump_thunks, call_thunks)
+		.is_some()
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn gadget_tail_rule(
+    instr: &Instruction,
+    rop: bool,
+    iret: bool,
+    sysret: bool,
+    sysexit: bool,
+    syscall: bool,
+    jop: bool,
+    cop: bool,
+    direct: bool,
+    noisy: bool,
+    ret_thunk: Option<u64>,
+    thunks: &[(String, Option<u64>)],
+    jump_thunks: &[(String, Option<u64>)],
+    call_thunks: &[(String, Option<u64>)]
+) -> Option<TailRule> {
 	if is_invalid(instr) {
-		return false;
+		return None;
 	}
 	if instr.flow_control() == FlowControl::Next {
-		return false;
+		return None;
 	}
     if rop && is_target_thunk(instr, ret_thunk, thunks, jump_thunks, call_thunks) {
-        return true;
+        return Some(TailRule::Thunk);
     }
 	if rop && is_ret(instr, ret_thunk) {
-		return true;
+		return Some(TailRule::Ret);
 	}
-	if sys && is_sys(instr) {
-		return true;
+	if is_sys(instr, iret, sysret, sysexit) {
+		return match instr.mnemonic() {
+			Mnemonic::Sysret | Mnemonic::Sysretq => Some(TailRule::SysSysret),
+			Mnemonic::Sysexit | Mnemonic::Sysexitq => Some(TailRule::SysSysexit),
+			_ => Some(TailRule::SysIret),
+		};
+	}
+	if syscall && is_syscall(instr) {
+		return Some(TailRule::Syscall);
+	}
+	if direct && is_direct_branch(instr) {
+		return Some(TailRule::Direct);
 	}
 	if jop && is_jop(instr, noisy) {
-		return true;
+		return Some(TailRule::JopJmp);
 	}
-	false
+	if cop && is_cop(instr, noisy) {
+		return Some(TailRule::JopCall);
+	}
+	None
 }
 
 pub fn is_rop_gadget_head(instr: &Instruction, noisy: bool) -> bool {
@@ -149,7 +241,65 @@ pub fn is_rop_gadget_head(instr: &Instruction, noisy: bool) -> bool {
 	}
 }
 
-pub fn is_stack_pivot_head(instr: &Instruction) -> bool {
+/// A conditional move (including the x87 `fcmovcc`), whose effect depends on flags which are rarely controllable in a chain
+pub fn is_cmov(instr: &Instruction) -> bool {
+	matches!(
+		instr.mnemonic(),
+		Mnemonic::Cmova
+			| Mnemonic::Cmovae
+			| Mnemonic::Cmovb
+			| Mnemonic::Cmovbe
+			| Mnemonic::Cmove
+			| Mnemonic::Cmovg
+			| Mnemonic::Cmovge
+			| Mnemonic::Cmovl
+			| Mnemonic::Cmovle
+			| Mnemonic::Cmovne
+			| Mnemonic::Cmovno
+			| Mnemonic::Cmovnp
+			| Mnemonic::Cmovns
+			| Mnemonic::Cmovo
+			| Mnemonic::Cmovp
+			| Mnemonic::Cmovs
+			| Mnemonic::Fcmovb
+			| Mnemonic::Fcmovbe
+			| Mnemonic::Fcmove
+			| Mnemonic::Fcmovnb
+			| Mnemonic::Fcmovnbe
+			| Mnemonic::Fcmovne
+			| Mnemonic::Fcmovnu
+			| Mnemonic::Fcmovu
+	)
+}
+
+// In 64-bit code a write to esp zero extends into rsp so still pivots, 16-bit sp only makes sense
+// in 16-bit code
+fn stack_pointers(bitness: Bitness) -> &'static [Register] {
+	match bitness {
+		Bitness::Bits64 => &[Register::RSP, Register::ESP],
+		Bitness::Bits32 => &[Register::ESP],
+		Bitness::Bits16 => &[Register::SP],
+	}
+}
+
+fn base_pointers(bitness: Bitness) -> &'static [Register] {
+	match bitness {
+		Bitness::Bits64 => &[Register::RBP, Register::EBP],
+		Bitness::Bits32 => &[Register::EBP],
+		Bitness::Bits16 => &[Register::BP],
+	}
+}
+
+/// `endbr64`, or `endbr32` outside of 64-bit code, which indirect branches must land on with CET
+pub fn is_landing_pad(instr: &Instruction, bitness: Bitness) -> bool {
+	match bitness {
+		Bitness::Bits64 => instr.mnemonic() == Mnemonic::Endbr64,
+		Bitness::Bits32 | Bitness::Bits16 => instr.mnemonic() == Mnemonic::Endbr32,
+	}
+}
+
+pub fn is_stack_pivot_head(instr: &Instruction, bitness: Bitness) -> bool {
+	let stack_pointers = stack_pointers(bitness);
 	let reg0 = instr.op0_register();
 	let kind1 = instr.op1_kind();
 	let reg1 = instr.op1_register();
@@ -182,7 +332,7 @@ pub fn is_stack_pivot_head(instr: &Instruction) -> bool {
 		| Mnemonic::Pop
 		| Mnemonic::Popa
 		| Mnemonic::Popad => {
-			matches!(reg0, Register::RSP | Register::ESP | Register::SP)
+			stack_pointers.contains(&reg0)
 				&& matches!(
 					kind1,
 					OpKind::Immediate8
@@ -190,25 +340,399 @@ pub fn is_stack_pivot_head(instr: &Instruction) -> bool {
 						| OpKind::Immediate32 | OpKind::Immediate64
 						| OpKind::Immediate8to16 | OpKind::Immediate8to32
 						| OpKind::Immediate8to64 | OpKind::Immediate32to64
-						| OpKind::Register
+						| OpKind::Register | OpKind::Memory
 				)
 		}
 		Mnemonic::Mov | Mnemonic::Movbe | Mnemonic::Movd => {
-			matches!(reg0, Register::RSP | Register::ESP | Register::SP)
-				&& (matches!(kind1, OpKind::Register) || instr.memory_base() != Register::None)
+			stack_pointers.contains(&reg0)
+				&& (matches!(kind1, OpKind::Register)
+					|| instr.memory_base() != Register::None
+					|| fixed_pivot(instr).is_some())
 		}
+		// `lea rsp, [rax+8]` moves the stack to wherever the address points
+		Mnemonic::Lea => stack_pointers.contains(&reg0),
+		// Either operand may be the stack pointer, including `xchg [rax], rsp` swapping it with memory
 		Mnemonic::Xadd | Mnemonic::Xchg => {
-			matches!(reg0, Register::RSP | Register::ESP | Register::SP)
-				|| matches!(reg1, Register::RSP | Register::ESP | Register::SP)
+			stack_pointers.contains(&reg0) || stack_pointers.contains(&reg1)
 		}
 		Mnemonic::Leave => true,
 		_ => false,
 	}
 }
 
+/// Stack pivots which are worth distinguishing from a generic write to the stack pointer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PivotKind {
+	/// `mov rsp, rbp` or `leave`, a frame teardown which pivots to wherever rbp points
+	RbpControlled,
+	/// `mov esp, 0x804c000`, a pivot to a known address
+	Fixed(u64),
+}
+
+impl Display for PivotKind {
+	fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::RbpControlled => write!(f, "rbp-pivot"),
+			Self::Fixed(address) => write!(f, "fixed-pivot {:#x}", address),
+		}
+	}
+}
+
+/// The address a `mov` of an immediate into the stack pointer pivots to, these are only really
+/// seen in 32-bit code where `mov esp, imm32` can hold any address
+pub fn fixed_pivot(instr: &Instruction) -> Option<u64> {
+	let immediate = matches!(
+		instr.op1_kind(),
+		OpKind::Immediate16 | OpKind::Immediate32 | OpKind::Immediate32to64 | OpKind::Immediate64
+	);
+	(instr.mnemonic() == Mnemonic::Mov
+		&& instr.op0_kind() == OpKind::Register
+		&& instr.op0_register().full_register() == Register::RSP
+		&& immediate)
+		.then(|| instr.immediate(1))
+}
+
+pub fn pivot_kind(instr: &Instruction) -> Option<PivotKind> {
+	if let Some(address) = fixed_pivot(instr) {
+		return Some(PivotKind::Fixed(address));
+	}
+	match instr.mnemonic() {
+		Mnemonic::Mov
+			if instr.op0_kind() == OpKind::Register
+				&& instr.op1_kind() == OpKind::Register
+				&& matches!(
+					(instr.op0_register(), instr.op1_register()),
+					(Register::RSP, Register::RBP) | (Register::ESP, Register::EBP)
+				) =>
+		{
+			Some(PivotKind::RbpControlled)
+		}
+		Mnemonic::Leave => Some(PivotKind::RbpControlled),
+		_ => None,
+	}
+}
+
+pub fn memory_load(instr: &Instruction) -> Option<(Register, Register, i64)> {
+	if !matches!(instr.mnemonic(), Mnemonic::Mov)
+		|| instr.op0_kind() != OpKind::Register
+		|| instr.op1_kind() != OpKind::Memory
+		|| instr.memory_index() != Register::None
+	{
+		return None;
+	}
+	let reg0 = instr.op0_register();
+	if !reg0.is_gpr() {
+		return None;
+	}
+	let base = instr.memory_base();
+	let displacement = if base.is_gpr64() {
+		instr.memory_displacement64() as i64
+	}
+	else if base.is_gpr32() {
+		instr.memory_displacement32() as i32 as i64
+	}
+	else {
+		return None;
+	};
+	Some((reg0, base, displacement))
+}
+
+/// A `mov dest, src` between general purpose registers, returned as full width registers
+pub fn register_move(instr: &Instruction) -> Option<(Register, Register)> {
+	if instr.mnemonic() != Mnemonic::Mov
+		|| instr.op0_kind() != OpKind::Register
+		|| instr.op1_kind() != OpKind::Register
+	{
+		return None;
+	}
+	let (dest, src) = (instr.op0_register(), instr.op1_register());
+	if !dest.is_gpr() || !src.is_gpr() {
+		return None;
+	}
+	Some((dest.full_register(), src.full_register()))
+}
+
+/// An `and`/`or`/`shl`/`shr`/`sar` of a general purpose register by an immediate, returning the
+/// operation, the full width register and the immediate
+pub fn bit_op(instr: &Instruction) -> Option<(Mnemonic, Register, u64)> {
+	if !matches!(
+		instr.mnemonic(),
+		Mnemonic::And | Mnemonic::Or | Mnemonic::Shl | Mnemonic::Shr | Mnemonic::Sar
+	) || instr.op0_kind() != OpKind::Register
+		|| !instr.op0_register().is_gpr()
+	{
+		return None;
+	}
+	let immediate = match instr.op1_kind() {
+		OpKind::Immediate8
+		| OpKind::Immediate16
+		| OpKind::Immediate32
+		| OpKind::Immediate64
+		| OpKind::Immediate8to16
+		| OpKind::Immediate8to32
+		| OpKind::Immediate8to64
+		| OpKind::Immediate32to64 => instr.immediate(1),
+		_ => return None,
+	};
+	Some((instr.mnemonic(), instr.op0_register().full_register(), immediate))
+}
+
+/// A `mov reg, imm` of a 32 or 64-bit register, returning the full register and the value it ends up
+/// holding, eg. `mov eax, 0x3b`
+pub fn immediate_load(instr: &Instruction) -> Option<(Register, u64)> {
+	if instr.mnemonic() != Mnemonic::Mov || instr.op0_kind() != OpKind::Register {
+		return None;
+	}
+	let dest = instr.op0_register();
+	if !dest.is_gpr32() && !dest.is_gpr64() {
+		return None;
+	}
+	let immediate = match instr.op1_kind() {
+		OpKind::Immediate32 => instr.immediate(1) as u32 as u64,
+		OpKind::Immediate64 | OpKind::Immediate32to64 => instr.immediate(1),
+		_ => return None,
+	};
+	Some((dest.full_register(), immediate))
+}
+
+/// The full register cleared by a zeroing idiom, `xor eax, eax` or `sub rax, rax`
+pub fn zeroed_register(instr: &Instruction) -> Option<Register> {
+	if !matches!(instr.mnemonic(), Mnemonic::Xor | Mnemonic::Sub)
+		|| instr.op0_kind() != OpKind::Register
+		|| instr.op1_kind() != OpKind::Register
+	{
+		return None;
+	}
+	let dest = instr.op0_register();
+	let zeroes = (dest.is_gpr32() || dest.is_gpr64()) && instr.op1_register() == dest;
+	zeroes.then(|| dest.full_register())
+}
+
+/// A `mov reg, rsp` or `lea reg, [rsp+disp]` which copies the stack pointer into another register,
+/// returning that register at full width. Copies truncated to fewer bits than the stack pointer
+/// (`mov eax, esp` in 64-bit code) are ignored
+pub fn stack_leak(instr: &Instruction) -> Option<Register> {
+	if instr.op0_kind() != OpKind::Register {
+		return None;
+	}
+	let stack_pointer = match instr.code_size() {
+		CodeSize::Code64 => Register::RSP,
+		_ => Register::ESP,
+	};
+	let dest = instr.op0_register();
+	let leaks = match instr.mnemonic() {
+		Mnemonic::Mov => {
+			instr.op1_kind() == OpKind::Register && instr.op1_register() == stack_pointer
+		}
+		Mnemonic::Lea => {
+			instr.memory_base() == stack_pointer && instr.memory_index() == Register::None
+		}
+		_ => false,
+	};
+	if !leaks
+		|| !dest.is_gpr()
+		|| dest.size() != stack_pointer.size()
+		|| dest.full_register() == Register::RSP
+	{
+		return None;
+	}
+	Some(dest.full_register())
+}
+
+/// A `mov [base+index*scale], value` write-what-where primitive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+	pub base: Register,
+	pub index: Register,
+	pub scale: u32,
+	pub value: Register,
+}
+
+pub fn memory_write(instr: &Instruction) -> Option<MemoryWrite> {
+	if !matches!(instr.mnemonic(), Mnemonic::Mov)
+		|| instr.op0_kind() != OpKind::Memory
+		|| instr.op1_kind() != OpKind::Register
+	{
+		return None;
+	}
+	let value = instr.op1_register();
+	let base = instr.memory_base();
+	let index = instr.memory_index();
+	if !value.is_gpr() || !(base.is_gpr() || index.is_gpr()) {
+		return None;
+	}
+	Some(MemoryWrite {
+		base,
+		index,
+		scale: instr.memory_index_scale(),
+		value,
+	})
+}
+
+/// Whether the instruction's destination operand is memory which it writes, eg. `mov [rsp], rax`
+/// or `xadd [rdi], eax` but not `cmp [rdi], eax`
+pub fn has_memory_destination(instr: &Instruction) -> bool {
+	if instr.op0_kind() != OpKind::Memory {
+		return false;
+	}
+	let mut factory = InstructionInfoFactory::new();
+	matches!(
+		factory.info(instr).op0_access(),
+		OpAccess::Write | OpAccess::CondWrite | OpAccess::ReadWrite | OpAccess::ReadCondWrite
+	)
+}
+
+pub fn stack_load(instr: &Instruction) -> Option<(Register, i64)> {
+	let (reg0, base, displacement) = memory_load(instr)?;
+	if matches!(reg0, Register::RSP | Register::ESP | Register::SP)
+		|| !matches!(base, Register::RSP | Register::RBP | Register::ESP | Register::EBP)
+	{
+		return None;
+	}
+	Some((reg0, displacement))
+}
+
+/// The bytes `ret imm16` releases on top of popping the return address, `None` for other
+/// instructions including a plain `ret`
+pub fn ret_immediate(instr: &Instruction) -> Option<u16> {
+	(instr.mnemonic() == Mnemonic::Ret && instr.op0_kind() == OpKind::Immediate16)
+		.then(|| instr.immediate16())
+}
+
+/// The net change an instruction makes to the stack pointer, `None` when it isn't a constant, eg.
+/// `add rsp, rax`, `leave` or `pop rsp`
+pub fn stack_effect(instr: &Instruction) -> Option<i64> {
+	let writes_rsp = instr.op0_kind() == OpKind::Register
+		&& instr.op0_register().full_register() == Register::RSP;
+	let signed = |value: u64| match instr.op0_register().size() {
+		4 => value as u32 as i32 as i64,
+		_ => value as i64,
+	};
+	match instr.mnemonic() {
+		Mnemonic::Add | Mnemonic::Sub if writes_rsp => {
+			let immediate = match instr.op1_kind() {
+				OpKind::Immediate8to16
+				| OpKind::Immediate8to32
+				| OpKind::Immediate8to64
+				| OpKind::Immediate16
+				| OpKind::Immediate32
+				| OpKind::Immediate32to64 => signed(instr.immediate(1)),
+				_ => return None,
+			};
+			match instr.mnemonic() {
+				Mnemonic::Add => Some(immediate),
+				_ => Some(-immediate),
+			}
+		}
+		Mnemonic::Lea
+			if writes_rsp
+				&& instr.memory_base().full_register() == Register::RSP
+				&& instr.memory_index() == Register::None =>
+		{
+			Some(signed(instr.memory_displacement64()))
+		}
+		Mnemonic::Pop if writes_rsp => None,
+		_ => match instr.stack_pointer_increment() {
+			0 if written_registers(instr).contains(&Register::RSP) => None,
+			increment => Some(increment as i64),
+		},
+	}
+}
+
+pub fn indirect_branch_registers(instr: &Instruction) -> Vec<Register> {
+	if !matches!(instr.mnemonic(), Mnemonic::Jmp | Mnemonic::Call) {
+		return Vec::new();
+	}
+	let registers = match instr.op0_kind() {
+		OpKind::Register => vec![instr.op0_register()],
+		OpKind::Memory => vec![instr.memory_base(), instr.memory_index()],
+		_ => Vec::new(),
+	};
+	registers
+		.into_iter()
+		.filter(|r| r.is_gpr())
+		.map(|r| r.full_register())
+		.collect()
+}
+
+/// General purpose registers read or written by an instruction, as full width registers
+pub fn used_registers(instr: &Instruction) -> Vec<Register> {
+	let mut factory = InstructionInfoFactory::new();
+	factory
+		.info(instr)
+		.used_registers()
+		.iter()
+		.map(|used| used.register())
+		.filter(|r| r.is_gpr())
+		.map(|r| r.full_register())
+		.collect()
+}
+
+/// The absolute address of the memory slot an indirect branch reads its target from, e.g. the GOT
+/// entry of `call [rip+0x1234]`
+pub fn indirect_branch_slot(instr: &Instruction) -> Option<u64> {
+	if !matches!(instr.mnemonic(), Mnemonic::Jmp | Mnemonic::Call) || instr.op0_kind() != OpKind::Memory {
+		return None;
+	}
+	if instr.is_ip_rel_memory_operand() {
+		return Some(instr.ip_rel_memory_address());
+	}
+	match (instr.memory_base(), instr.memory_index()) {
+		(Register::None, Register::None) => Some(instr.memory_displacement64()),
+		_ => None,
+	}
+}
+
+/// General purpose registers an instruction reads, including those addressing its memory operands
+pub fn read_registers(instr: &Instruction) -> Vec<Register> {
+	let mut factory = InstructionInfoFactory::new();
+	factory
+		.info(instr)
+		.used_registers()
+		.iter()
+		.filter(|used| {
+			matches!(
+				used.access(),
+				OpAccess::Read | OpAccess::CondRead | OpAccess::ReadWrite | OpAccess::ReadCondWrite
+			)
+		})
+		.map(|used| used.register())
+		.filter(|r| r.is_gpr())
+		.map(|r| r.full_register())
+		.collect()
+}
+
+/// Whether an instruction writes to memory, including implicit writes such as `push` and `stos`
+pub fn writes_memory(instr: &Instruction) -> bool {
+	let mut factory = InstructionInfoFactory::new();
+	factory.info(instr).used_memory().iter().any(|used| {
+		matches!(
+			used.access(),
+			OpAccess::Write | OpAccess::CondWrite | OpAccess::ReadWrite | OpAccess::ReadCondWrite
+		)
+	})
+}
+
+pub fn written_registers(instr: &Instruction) -> Vec<Register> {
+	let mut factory = InstructionInfoFactory::new();
+	factory
+		.info(instr)
+		.used_registers()
+		.iter()
+		.filter(|used| {
+			matches!(
+				used.access(),
+				OpAccess::Write | OpAccess::CondWrite | OpAccess::ReadWrite | OpAccess::ReadCondWrite
+			)
+		})
+		.map(|used| used.register().full_register())
+		.collect()
+}
+
 pub fn is_stack_pivot_tail(instr: &Instruction, ret_thunk: Option<u64>) -> bool { is_ret(instr, ret_thunk) }
 
-pub fn is_base_pivot_head(instr: &Instruction) -> bool {
+pub fn is_base_pivot_head(instr: &Instruction, bitness: Bitness) -> bool {
+	let base_pointers = base_pointers(bitness);
 	let reg0 = instr.op0_register();
 	let kind1 = instr.op1_kind();
 	let reg1 = instr.op1_register();
@@ -241,7 +765,7 @@ pub fn is_base_pivot_head(instr: &Instruction) -> bool {
 		| Mnemonic::Pop
 		| Mnemonic::Popa
 		| Mnemonic::Popad => {
-			matches!(reg0, Register::RBP | Register::EBP | Register::BP)
+			base_pointers.contains(&reg0)
 				&& matches!(
 					kind1,
 					OpKind::Immediate8
@@ -253,14 +777,169 @@ pub fn is_base_pivot_head(instr: &Instruction) -> bool {
 				)
 		}
 		Mnemonic::Mov | Mnemonic::Movbe | Mnemonic::Movd => {
-			matches!(reg0, Register::RBP | Register::EBP | Register::BP)
+			base_pointers.contains(&reg0)
 				&& (matches!(kind1, OpKind::Register) || instr.memory_base() != Register::None)
 		}
 		Mnemonic::Xadd | Mnemonic::Xchg => {
-			matches!(reg0, Register::RBP | Register::EBP | Register::BP)
-				|| matches!(reg1, Register::RBP | Register::EBP | Register::BP)
+			base_pointers.contains(&reg0) || base_pointers.contains(&reg1)
 		}
 		Mnemonic::Enter => true,
 		_ => false,
 	}
 }
+
+/// Looks up a register by its lowercase assembly name, e.g. `rdi` or `r8d`
+pub fn parse_register(name: &str) -> Option<Register> {
+	let name = name.trim().to_lowercase();
+	Register::values().find(|r| *r != Register::None && format!("{:?}", r).to_lowercase() == name)
+}
+
+/// Looks up a mnemonic by its lowercase assembly name, e.g. `pop` or `xchg`
+pub fn parse_mnemonic(name: &str) -> Option<Mnemonic> {
+	let name = name.trim().to_lowercase();
+	Mnemonic::values().find(|m| *m != Mnemonic::INVALID && format!("{:?}", m).to_lowercase() == name)
+}
+
+pub fn pushed_register(instr: &Instruction) -> Option<Register> {
+	match (instr.mnemonic(), instr.op0_kind()) {
+		(Mnemonic::Push, OpKind::Register) => Some(instr.op0_register()),
+		_ => None,
+	}
+}
+
+/// Matches the start of common function prologues, `endbr64` or `push rbp; mov rbp, rsp`
+pub fn is_prologue(instrs: &[Instruction]) -> bool {
+	match instrs {
+		[i, ..] if matches!(i.mnemonic(), Mnemonic::Endbr64 | Mnemonic::Endbr32) => true,
+		[push, mov, ..] => {
+			push.mnemonic() == Mnemonic::Push
+				&& matches!(push.op0_register(), Register::RBP | Register::EBP)
+				&& mov.mnemonic() == Mnemonic::Mov
+				&& matches!(
+					(mov.op0_register(), mov.op1_register()),
+					(Register::RBP, Register::RSP) | (Register::EBP, Register::ESP)
+				)
+		}
+		_ => false,
+	}
+}
+
+/// Registers used by a `movs`/`stos` string copy (with any `rep` count), `cld` and `std` use none
+pub fn string_op_registers(instr: &Instruction) -> Option<Vec<Register>> {
+	match instr.mnemonic() {
+		Mnemonic::Cld | Mnemonic::Std => Some(Vec::new()),
+		Mnemonic::Movsb
+		| Mnemonic::Movsw
+		| Mnemonic::Movsd
+		| Mnemonic::Movsq
+		| Mnemonic::Stosb
+		| Mnemonic::Stosw
+		| Mnemonic::Stosd
+		| Mnemonic::Stosq
+			if instr.code().is_string_instruction() =>
+		{
+			let mut factory = InstructionInfoFactory::new();
+			let registers = factory
+				.info(instr)
+				.used_registers()
+				.iter()
+				.map(|used| used.register())
+				.filter(|r| r.is_gpr())
+				.collect();
+			Some(registers)
+		}
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use iced_x86::{Decoder, DecoderOptions};
+
+	fn decode(bitness: u32, bytes: &[u8]) -> Instruction {
+		let instr = Decoder::new(bitness, bytes, DecoderOptions::NONE).decode();
+		assert_eq!(instr.len(), bytes.len(), "{:02x?} is not a single instruction", bytes);
+		instr
+	}
+
+	fn is_pivot(bytes: &[u8]) -> bool { is_stack_pivot_head(&decode(64, bytes), Bitness::Bits64) }
+
+	#[test]
+	fn lea_into_rsp_is_pivot() {
+		// lea rsp, [rax+8]
+		assert!(is_pivot(&[0x48, 0x8d, 0x60, 0x08]));
+		// lea rsp, [rdi+rsi*8]
+		assert!(is_pivot(&[0x48, 0x8d, 0x24, 0xf7]));
+		// lea rax, [rsp+8] only reads the stack pointer
+		assert!(!is_pivot(&[0x48, 0x8d, 0x44, 0x24, 0x08]));
+	}
+
+	#[test]
+	fn add_sub_memory_into_rsp_is_pivot() {
+		// add rsp, [rax]
+		assert!(is_pivot(&[0x48, 0x03, 0x20]));
+		// sub rsp, [rax+0x10]
+		assert!(is_pivot(&[0x48, 0x2b, 0x60, 0x10]));
+		// add rax, [rsp]
+		assert!(!is_pivot(&[0x48, 0x03, 0x04, 0x24]));
+	}
+
+	#[test]
+	fn add_register_into_rsp_is_pivot() {
+		// add rsp, rax
+		assert!(is_pivot(&[0x48, 0x01, 0xc4]));
+		// sub rsp, rcx
+		assert!(is_pivot(&[0x48, 0x29, 0xcc]));
+		// add rax, rsp
+		assert!(!is_pivot(&[0x48, 0x01, 0xe0]));
+	}
+
+	#[test]
+	fn xchg_rsp_with_memory_is_pivot() {
+		// xchg [rax], rsp
+		assert!(is_pivot(&[0x48, 0x87, 0x20]));
+		// xchg rsp, rax
+		assert!(is_pivot(&[0x48, 0x94]));
+		// xchg [rax], rbx
+		assert!(!is_pivot(&[0x48, 0x87, 0x18]));
+	}
+
+	#[test]
+	fn pivots_in_32_bit_code() {
+		// mov esp, eax
+		assert!(is_stack_pivot_head(&decode(32, &[0x89, 0xc4]), Bitness::Bits32));
+		// mov sp, ax only writes the low half of the stack pointer
+		assert!(!is_stack_pivot_head(&decode(32, &[0x66, 0x89, 0xc4]), Bitness::Bits32));
+		// pop sp
+		assert!(!is_stack_pivot_head(&decode(32, &[0x66, 0x5c]), Bitness::Bits32));
+	}
+
+	#[test]
+	fn fixed_pivot_in_32_bit_code() {
+		// mov esp, 0x804c000
+		let instr = decode(32, &[0xbc, 0x00, 0xc0, 0x04, 0x08]);
+		assert!(is_stack_pivot_head(&instr, Bitness::Bits32));
+		assert_eq!(fixed_pivot(&instr), Some(0x804c000));
+		assert_eq!(pivot_kind(&instr), Some(PivotKind::Fixed(0x804c000)));
+		// mov eax, 0x804c000
+		assert_eq!(fixed_pivot(&decode(32, &[0xb8, 0x00, 0xc0, 0x04, 0x08])), None);
+	}
+
+	#[test]
+	fn pivot_registers_follow_bitness() {
+		let stack = |bits, bytes: &[u8], mode| is_stack_pivot_head(&decode(bits, bytes), mode);
+		let base = |bits, bytes: &[u8], mode| is_base_pivot_head(&decode(bits, bytes), mode);
+		// mov sp, ax leaves the upper bits of rsp alone in 64-bit code
+		assert!(!stack(64, &[0x66, 0x89, 0xc4], Bitness::Bits64));
+		// mov esp, eax zero extends into rsp
+		assert!(stack(64, &[0x89, 0xc4], Bitness::Bits64));
+		// In 16-bit code sp is the whole stack pointer, an operand size prefixed esp write is not
+		assert!(stack(16, &[0x89, 0xc4], Bitness::Bits16));
+		assert!(!stack(16, &[0x66, 0x89, 0xc4], Bitness::Bits16));
+		// mov bp, ax and mov ebp, eax
+		assert!(!base(64, &[0x66, 0x89, 0xc5], Bitness::Bits64));
+		assert!(base(32, &[0x89, 0xc5], Bitness::Bits32));
+		assert!(!base(32, &[0x66, 0x89, 0xc5], Bitness::Bits32));
+	}
+}